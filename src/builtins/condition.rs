@@ -0,0 +1,200 @@
+use std::{
+    cmp::Ordering,
+    ffi::CString,
+    fs,
+    io::{Error, ErrorKind, Result},
+    os::unix::fs::MetadataExt,
+};
+
+const UNARY_OPS: &[&str] = &["-e", "-f", "-d", "-s", "-x", "-r", "-w", "-L", "-h", "-n", "-z"];
+
+const BINARY_OPS: &[&str] = &[
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot",
+];
+
+pub fn is_unary_op(op: &str) -> bool {
+    UNARY_OPS.contains(&op)
+}
+
+/// Binary operators of `test`; `[[ ]]` also has `=~`
+pub fn is_binary_op(op: &str) -> bool {
+    BINARY_OPS.contains(&op)
+}
+
+fn error(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("shesh: {}", message))
+}
+
+fn access(path: &str, mode: i32) -> bool {
+    CString::new(path).is_ok_and(|path| unsafe { libc::access(path.as_ptr(), mode) } == 0)
+}
+
+/// A file test or string check such as `-f file` or `-z string`
+pub fn unary(op: &str, arg: &str) -> Result<bool> {
+    Ok(match op {
+        "-e" => fs::metadata(arg).is_ok(),
+        "-f" => fs::metadata(arg).is_ok_and(|m| m.is_file()),
+        "-d" => fs::metadata(arg).is_ok_and(|m| m.is_dir()),
+        "-s" => fs::metadata(arg).is_ok_and(|m| m.len() > 0),
+        "-L" | "-h" => fs::symlink_metadata(arg).is_ok_and(|m| m.is_symlink()),
+        "-x" => access(arg, libc::X_OK),
+        "-r" => access(arg, libc::R_OK),
+        "-w" => access(arg, libc::W_OK),
+        "-n" => !arg.is_empty(),
+        "-z" => arg.is_empty(),
+        _ => return Err(error(format!("{}: unary operator expected", op))),
+    })
+}
+
+fn integer(arg: &str) -> Result<i64> {
+    arg.trim()
+        .parse()
+        .map_err(|_| error(format!("{}: integer expression expected", arg)))
+}
+
+/// Modification time of a file, or `None` if it does not exist
+fn modified(path: &str) -> Option<(i64, i64)> {
+    fs::metadata(path).ok().map(|m| (m.mtime(), m.mtime_nsec()))
+}
+
+/// A comparison of two strings, numbers or files; `=` and `!=` compare literally
+pub fn binary(left: &str, op: &str, right: &str) -> Result<bool> {
+    let numbers = || Ok::<_, Error>(integer(left)?.cmp(&integer(right)?));
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" => numbers()? == Ordering::Equal,
+        "-ne" => numbers()? != Ordering::Equal,
+        "-lt" => numbers()? == Ordering::Less,
+        "-le" => numbers()? != Ordering::Greater,
+        "-gt" => numbers()? == Ordering::Greater,
+        "-ge" => numbers()? != Ordering::Less,
+        // A file that exists is newer than one that does not
+        "-nt" => modified(left) > modified(right),
+        "-ot" => modified(left) < modified(right),
+        _ => return Err(error(format!("{}: binary operator expected", op))),
+    })
+}
+
+/// Match `text` against an extended regular expression
+pub fn regex_matches(pattern: &str, text: &str) -> Result<bool> {
+    let invalid = || error(format!("{}: invalid regular expression", pattern));
+    let pattern = CString::new(pattern).map_err(|_| invalid())?;
+    let text = CString::new(text).map_err(|_| invalid())?;
+
+    unsafe {
+        let mut regex: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut regex, pattern.as_ptr(), libc::REG_EXTENDED | libc::REG_NOSUB) != 0 {
+            return Err(invalid());
+        }
+        let matched = libc::regexec(&regex, text.as_ptr(), 0, std::ptr::null_mut(), 0) == 0;
+        libc::regfree(&mut regex);
+        Ok(matched)
+    }
+}
+
+/// Recursive descent over the arguments of `test`, where `-o` binds more
+/// loosely than `-a`, which binds more loosely than `!`
+struct Test<'a> {
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl Test<'_> {
+    fn or(&mut self) -> Result<bool> {
+        let mut result = self.and()?;
+        while self.args.get(self.pos) == Some(&"-o") {
+            self.pos += 1;
+            let right = self.and()?;
+            result = result || right;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool> {
+        let mut result = self.not()?;
+        while self.args.get(self.pos) == Some(&"-a") {
+            self.pos += 1;
+            let right = self.not()?;
+            result = result && right;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool> {
+        // A lone `!` is just a non-empty string
+        if self.args.get(self.pos) == Some(&"!") && self.pos + 1 < self.args.len() {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool> {
+        let args = self.args;
+        let Some(&arg) = args.get(self.pos) else {
+            return Err(error("test: argument expected".to_string()));
+        };
+
+        // Comparisons come first, so that `[ "(" = "(" ]` compares strings
+        if let Some(&op) = args.get(self.pos + 1)
+            && is_binary_op(op)
+            && let Some(&right) = args.get(self.pos + 2)
+        {
+            self.pos += 3;
+            return binary(arg, op, right);
+        }
+
+        if arg == "(" && self.pos + 1 < args.len() {
+            self.pos += 1;
+            let result = self.or()?;
+            if args.get(self.pos) != Some(&")") {
+                return Err(error("test: `)' expected".to_string()));
+            }
+            self.pos += 1;
+            return Ok(result);
+        }
+
+        if is_unary_op(arg) && let Some(&operand) = args.get(self.pos + 1) {
+            self.pos += 2;
+            return unary(arg, operand);
+        }
+
+        self.pos += 1;
+        Ok(!arg.is_empty())
+    }
+}
+
+/// `test expr` and `[ expr ]`. The status is 0 when the expression is true,
+/// 1 when it is false and 2 when it cannot be evaluated.
+pub fn handle_test(builtin: &str, args: &[&str]) -> Result<i32> {
+    let args = match (builtin, args.split_last()) {
+        ("[", Some((&"]", rest))) => rest,
+        ("[", _) => {
+            eprintln!("shesh: [: missing `]'");
+            return Ok(2);
+        }
+        _ => args,
+    };
+
+    // No arguments is false
+    if args.is_empty() {
+        return Ok(1);
+    }
+
+    let mut test = Test { args, pos: 0 };
+    let result = test.or().and_then(|result| match args.get(test.pos) {
+        Some(extra) => Err(error(format!("{}: {}: unexpected argument", builtin, extra))),
+        None => Ok(result),
+    });
+
+    match result {
+        Ok(result) => Ok(if result { 0 } else { 1 }),
+        Err(e) => {
+            eprintln!("{}", e);
+            Ok(2)
+        }
+    }
+}
@@ -1,15 +1,43 @@
+pub mod condition;
 mod tools;
-mod symbols;
 
 use std::io::Result;
+use crate::{jobs, options, shell};
 
-pub fn handle_command(cmd: &str, args: &[&str]) -> Option<Result<()>> {
+/// Commands the shell runs itself rather than looking up in `PATH`
+const BUILTINS: &[&str] = &[
+    "cd", "alias", "export", "unset", "set", "shift", "source", ".", "break", "continue",
+    "return", "local", "functions", "test", "[", "jobs", "fg", "bg", "exit",
+];
+
+pub fn is_builtin(cmd: &str) -> bool {
+    BUILTINS.contains(&cmd)
+}
+
+pub fn handle_command(cmd: &str, args: &[&str]) -> Option<Result<i32>> {
     match cmd {
-        "cd" => Some(tools::change_directory(args.first().unwrap_or(&"~"))),
-        "alias" => Some(tools::handle_alias_cmd(args)),
-        "export" => Some(tools::handle_export(args)),
-        "exit" => std::process::exit(0),
-        ">" | ">>" | "<" | "|" => Some(symbols::handle_symbol(cmd, args)),
+        "cd" => Some(tools::change_directory(args.first().unwrap_or(&"~")).map(|_| 0)),
+        "alias" => Some(tools::handle_alias_cmd(args).map(|_| 0)),
+        "export" => Some(tools::handle_export(args).map(|_| 0)),
+        "unset" => Some(tools::handle_unset(args).map(|_| 0)),
+        "set" => Some(options::handle_set(args).map(|_| 0)),
+        "shift" => Some(tools::handle_shift(args).map(|_| 0)),
+        "source" | "." => Some(tools::handle_source(cmd, args)),
+        "break" | "continue" => Some(tools::handle_loop_control(cmd, args).map(|_| 0)),
+        "return" => Some(tools::handle_return(args)),
+        "local" => Some(tools::handle_local(args).map(|_| 0)),
+        "functions" => Some(tools::list_functions().map(|_| 0)),
+        "test" | "[" => Some(condition::handle_test(cmd, args)),
+        "jobs" => Some(jobs::list().map(|_| 0)),
+        "fg" => Some(jobs::foreground(args.first().copied())),
+        "bg" => Some(jobs::background(args.first().copied()).map(|_| 0)),
+        "exit" => std::process::exit(match args.first() {
+            None => shell::last_status(),
+            Some(code) => code.parse().unwrap_or_else(|_| {
+                eprintln!("shesh: exit: {}: numeric argument required", code);
+                2
+            }),
+        }),
         _ => None
     }
 }
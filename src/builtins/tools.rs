@@ -1,6 +1,8 @@
+use std::fs;
 use std::io::Result;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use crate::commands;
+use crate::{commands, functions, script, shell::{self, Flow}, vars};
 
 static ALIASES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
 static PREV_DIR: Mutex<Option<String>> = Mutex::new(None);
@@ -81,30 +83,201 @@ pub fn change_directory(dir: &str) -> Result<()> {
     Ok(())
 }
 
-// pub fn handle_export(args: &[&str]) -> Result<()> {
-//     if let Some(arg) = args.first() {
-//         if let Some((var, value)) = arg.split_once('=') {
-//             // Actually set the environment variable
-//             std::env::set_var(var, value);
-//             return Ok(());
-//         }
-//     }
-//     Err(std::io::Error::new(
-//         std::io::ErrorKind::InvalidInput,
-//         "Usage: export VAR=value"
-//     ))
-// }
+fn quote_value(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn invalid_name(builtin: &str, name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("shesh: {}: `{}': not a valid identifier", builtin, name)
+    )
+}
 
 pub fn handle_export(args: &[&str]) -> Result<()> {
-    if let Some(arg) = args.first() {
-        if let Some((var, value)) = arg.split_once('=') {
-            // Just acknowledge the export command without actually setting the variable
-            println!("export {}={}", var, value);
-            return Ok(());
+    if args.is_empty() || args == ["-p"] {
+        for (name, value) in vars::exported() {
+            match value {
+                Some(value) => println!("export {}={}", name, quote_value(&value)),
+                None => println!("export {}", name),
+            }
         }
+        return Ok(());
     }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::InvalidInput,
-        "Usage: export VAR=value"
-    ))
+
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !vars::is_valid_name(name) {
+            return Err(invalid_name("export", arg));
+        }
+        vars::export(name, value);
+    }
+    Ok(())
+}
+
+pub fn handle_unset(args: &[&str]) -> Result<()> {
+    // `unset -f` removes functions instead of variables
+    if let Some((&"-f", names)) = args.split_first() {
+        names.iter().for_each(|name| functions::unset(name));
+        return Ok(());
+    }
+
+    for name in args.iter().filter(|a| **a != "-v") {
+        if !vars::is_valid_name(name) {
+            return Err(invalid_name("unset", name));
+        }
+        vars::unset(name);
+    }
+    Ok(())
+}
+
+/// The `shift` builtin
+pub fn handle_shift(args: &[&str]) -> Result<()> {
+    let count = match args.first() {
+        None => 1,
+        Some(n) => n.parse().map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("shesh: shift: {}: numeric argument required", n)
+        ))?,
+    };
+
+    if !vars::shift(count) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "shesh: shift: shift count out of range"
+        ));
+    }
+    Ok(())
+}
+
+/// `break [n]` and `continue [n]`: leave or restart the `n`th enclosing loop
+pub fn handle_loop_control(builtin: &str, args: &[&str]) -> Result<()> {
+    let depth = shell::loop_depth();
+    if depth == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("shesh: {}: only meaningful in a loop", builtin)
+        ));
+    }
+
+    let count = match args.first() {
+        None => 1,
+        Some(n) => match n.parse::<usize>() {
+            Ok(0) => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("shesh: {}: {}: loop count out of range", builtin, n)
+            )),
+            Ok(count) => count,
+            Err(_) => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("shesh: {}: {}: numeric argument required", builtin, n)
+            )),
+        },
+    };
+
+    // Counting past the outermost loop leaves that one
+    let count = count.min(depth);
+    shell::set_flow(Some(if builtin == "break" { Flow::Break(count) } else { Flow::Continue(count) }));
+    Ok(())
+}
+
+/// `return [n]`: leave the running function or sourced file with status `n`,
+/// or with the status of the last command
+pub fn handle_return(args: &[&str]) -> Result<i32> {
+    if shell::call_depth() == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "shesh: return: can only return from a function or sourced script"
+        ));
+    }
+
+    let status = match args.first() {
+        None => shell::last_status(),
+        Some(n) => n.parse::<i32>().map_err(|_| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("shesh: return: {}: numeric argument required", n)
+        ))? & 0xff,
+    };
+    shell::set_flow(Some(Flow::Return));
+    Ok(status)
+}
+
+/// The `functions` builtin: print the names of the defined functions
+pub fn list_functions() -> Result<()> {
+    for name in functions::names() {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+/// `local name[=value]...`: variables that only last until the function returns
+pub fn handle_local(args: &[&str]) -> Result<()> {
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !vars::is_valid_name(name) {
+            return Err(invalid_name("local", arg));
+        }
+        if !vars::make_local(name, value) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "shesh: local: can only be used in a function"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Where `source` finds a file: a name with a slash is used as given, others
+/// are looked up in `PATH` and then in the current directory
+fn find_source(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+
+    vars::get("PATH").unwrap_or_default()
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| path.is_file())
+        .or_else(|| Path::new(name).is_file().then(|| PathBuf::from(name)))
+}
+
+/// The `source` and `.` builtins: run a file in the current shell
+pub fn handle_source(builtin: &str, args: &[&str]) -> Result<i32> {
+    let Some((name, params)) = args.split_first() else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("shesh: {}: filename argument required", builtin)
+        ));
+    };
+
+    let path = find_source(name).ok_or_else(|| std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("shesh: {}: {}: file not found", builtin, name)
+    ))?;
+    let content = fs::read_to_string(&path)
+        .map_err(|e| std::io::Error::new(e.kind(), format!("shesh: {}: {}", name, e)))?;
+
+    // Arguments replace the positional parameters until the file is done
+    let saved = (!params.is_empty())
+        .then(|| vars::set_positional(params.iter().map(|p| p.to_string()).collect()));
+    let status = shell::in_call(|| script::run_lines(name, content.lines().map(str::to_string)));
+    if let Some(saved) = saved {
+        vars::set_positional(saved);
+    }
+    Ok(status)
 }
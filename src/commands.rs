@@ -1,278 +1,705 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    fs,
+    io::{Error, ErrorKind, Read, Result},
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::Mutex,
+};
 
 use crate::{
-    builtins,
-    shell
+    builtins::{self, condition},
+    functions,
+    glob,
+    jobs,
+    lexer::{self, Word, WordPart},
+    options,
+    parser,
+    parser::{
+        AndOr, CaseEnd, Command, CommandSeparator, CompoundCommand, CondExpr, FunctionBody,
+        List, Pipeline, RedirectNode, RedirectOp, SimpleCommand,
+    },
+    shell::{self, Flow, Stage},
+    vars
 };
 
-#[derive(Debug, PartialEq)]
-pub enum CommandSeparator {
-    AndAnd,    // &&
-    SemiColon, // ;
-    Background, // &
-    None,
+/// A redirection of one fd, ready to apply
+#[derive(Debug, Clone)]
+pub enum Redirect {
+    Output(i32, String), // n>file
+    Append(i32, String), // n>>file
+    Input(i32, String),  // n<file
+    Dup(i32, i32),       // n>&m or n<&m
+    Close(i32),          // n>&- or n<&-
+    Text(i32, String),   // n<<word or n<<<word, read from a pipe
 }
 
+/// A character of a word that brace expansion may act on, or a piece it must leave alone
 #[derive(Debug, Clone)]
-pub enum Redirect {
-    Output(String),
-    Append(String),
-    Input(String),
+enum BraceUnit {
+    Char(char),
+    Opaque(WordPart),
 }
 
-#[derive(Debug)]
-pub struct ParsedCommand {
-    pub cmd: Vec<String>,
-    pub redirects: Vec<Redirect>,
+/// Break a word into brace units; quoted parts and `$` constructs stay opaque
+fn brace_units(word: &Word) -> Vec<BraceUnit> {
+    let mut units = Vec::new();
+
+    for part in &word.parts {
+        let WordPart::Bare(text) = part else {
+            units.push(BraceUnit::Opaque(part.clone()));
+            continue;
+        };
+
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let construct = match c {
+                '$' if chars.next_if_eq(&'(').is_some() => {
+                    lexer::read_paren_body(&mut chars).map(|body| format!("$({})", body))
+                }
+                '$' if chars.next_if_eq(&'{').is_some() => {
+                    lexer::read_brace_body(&mut chars).map(|body| format!("${{{}}}", body))
+                }
+                '`' => lexer::read_backquoted(&mut chars).map(|body| format!("`{}`", body)),
+                _ => {
+                    units.push(BraceUnit::Char(c));
+                    continue;
+                }
+            };
+            // The lexer has already checked these are terminated
+            units.push(BraceUnit::Opaque(WordPart::Bare(construct.unwrap_or_default())));
+        }
+    }
+    units
 }
 
-pub fn parse_input(input: &str) -> Vec<String> {
+fn units_to_word(units: &[BraceUnit]) -> Word {
     let mut parts = Vec::new();
-    let mut buffer = String::new();
-    let mut in_quote = None;
-    let mut escape = false;
-
-    for c in input.chars() {
-        if escape {
-            buffer.push(c);
-            escape = false;
-            continue;
+
+    for unit in units {
+        match (unit, parts.last_mut()) {
+            (BraceUnit::Char(c), Some(WordPart::Bare(s))) => s.push(*c),
+            (BraceUnit::Char(c), _) => parts.push(WordPart::Bare(c.to_string())),
+            (BraceUnit::Opaque(part), _) => parts.push(part.clone()),
         }
+    }
+    Word { parts }
+}
 
-        match c {
-            '\\' => escape = true,
-            '"' | '\'' => {
-                if in_quote == Some(c) {
-                    in_quote = None;
-                } else if in_quote.is_none() {
-                    in_quote = Some(c);
-                } else {
-                    buffer.push(c);
+/// Parse one end of a sequence: an integer, or a single letter given as its
+/// code point along with `true`
+fn sequence_bound(text: &str) -> Option<(i64, bool)> {
+    if let Ok(n) = text.parse::<i64>() {
+        return Some((n, false));
+    }
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphabetic() => Some((c as i64, true)),
+        _ => None,
+    }
+}
+
+/// The most elements a `{x..y}` sequence may expand to
+const MAX_SEQUENCE: u64 = 100_000;
+
+/// Expand the body of a `{x..y}` or `{x..y..step}` sequence
+fn expand_sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (start, end, step) = match parts[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None,
+    };
+
+    let (first, char_range) = sequence_bound(start)?;
+    let (last, char_end) = sequence_bound(end)?;
+    if char_range != char_end {
+        return None;
+    }
+
+    // A leading zero on either end pads every number to the same width
+    let padded = |s: &str| s.trim_start_matches('-').len() > 1
+        && s.trim_start_matches('-').starts_with('0');
+    let width = if !char_range && (padded(start) || padded(end)) {
+        start.len().max(end.len())
+    } else {
+        0
+    };
+
+    // Sequences too long to be useful stay literal rather than exhausting memory
+    let count = (first.abs_diff(last) / step).checked_add(1)?;
+    if count > MAX_SEQUENCE {
+        return None;
+    }
+    let direction = if first <= last { 1 } else { -1 };
+    Some((0..count)
+        // Every element lies between the two ends, so it fits back in an i64
+        .map(|i| (first as i128 + direction * (i as i128 * step as i128)) as i64)
+        .map(|n| match char_range {
+            true => char::from_u32(n as u32).map(String::from).unwrap_or_default(),
+            false => format!("{:0width$}", n, width = width),
+        })
+        .collect())
+}
+
+/// Find the first `{...}` group that is either a list with a top-level comma or
+/// a sequence, returning its bounds and the alternatives it stands for
+fn find_brace_group(units: &[BraceUnit]) -> Option<(usize, usize, Vec<Vec<BraceUnit>>)> {
+    let is = |i: usize, c: char| matches!(units[i], BraceUnit::Char(u) if u == c);
+
+    for open in (0..units.len()).filter(|&i| is(i, '{')) {
+        let mut depth = 0;
+        let mut commas = Vec::new();
+
+        for i in open + 1..units.len() {
+            if is(i, '{') {
+                depth += 1;
+            } else if is(i, '}') && depth > 0 {
+                depth -= 1;
+            } else if is(i, '}') {
+                if !commas.is_empty() {
+                    let bounds: Vec<usize> = [open].into_iter().chain(commas).chain([i]).collect();
+                    let alternatives = bounds.windows(2)
+                        .map(|w| units[w[0] + 1..w[1]].to_vec())
+                        .collect();
+                    return Some((open, i, alternatives));
                 }
-            }
-            ' ' if in_quote.is_none() => {
-                if !buffer.is_empty() {
-                    parts.push(buffer.clone());
-                    buffer.clear();
+
+                let body: Option<String> = units[open + 1..i].iter()
+                    .map(|unit| match unit {
+                        BraceUnit::Char(c) => Some(*c),
+                        BraceUnit::Opaque(_) => None,
+                    })
+                    .collect();
+                if let Some(items) = body.as_deref().and_then(expand_sequence) {
+                    // Generated text is literal, even a character like ` in {Z..a}
+                    let alternatives = items.into_iter()
+                        .map(|item| vec![BraceUnit::Opaque(WordPart::SingleQuoted(item))])
+                        .collect();
+                    return Some((open, i, alternatives));
                 }
+                break;
+            } else if is(i, ',') && depth == 0 {
+                commas.push(i);
             }
-            _ => buffer.push(c),
         }
     }
+    None
+}
 
-    if !buffer.is_empty() {
-        parts.push(buffer);
-    }
-    parts
+fn expand_brace_units(units: &[BraceUnit]) -> Vec<Vec<BraceUnit>> {
+    let Some((open, close, alternatives)) = find_brace_group(units) else {
+        return vec![units.to_vec()];
+    };
+
+    alternatives.iter()
+        .flat_map(|alternative| {
+            expand_brace_units(&[&units[..open], alternative, &units[close + 1..]].concat())
+        })
+        .collect()
 }
 
-pub fn split_commands(input: &str) -> Vec<(String, CommandSeparator)> {
-    let mut tokens = Vec::new();
-    let mut current = String::new();
-    let mut in_quote = None;
-    let mut escape = false;
-    let mut chars = input.chars().peekable();
+/// Expand `{a,b}` lists and `{x..y}` sequences in the unquoted parts of a word
+pub fn expand_braces(word: &Word) -> Vec<Word> {
+    expand_brace_units(&brace_units(word))
+        .iter()
+        .map(|units| units_to_word(units))
+        .collect()
+}
 
-    while let Some(c) = chars.next() {
-        if escape {
-            current.push(c);
-            escape = false;
-            continue;
+/// One field of an expanded word
+struct Field {
+    text: String,
+    pattern: String, // the text with quoted wildcard characters escaped
+    glob: bool,      // contains an unquoted `*` or `?`, or a closed `[...]`
+}
+
+/// Collects the fields a word expands to
+struct Fields {
+    fields: Vec<Field>,
+    current: String,
+    pattern: String,
+    started: bool,
+    split: bool,
+}
+
+impl Fields {
+    fn new(split: bool) -> Self {
+        Self {
+            fields: Vec::new(),
+            current: String::new(),
+            pattern: String::new(),
+            started: false,
+            split,
         }
+    }
 
-        match c {
-            '\\' => escape = true,
-            '"' | '\'' => {
-                if in_quote == Some(c) {
-                    in_quote = None;
-                } else if in_quote.is_none() {
-                    in_quote = Some(c);
-                }
-                current.push(c);
-            }
-            ';' if in_quote.is_none() => {
-                tokens.push((current.trim().to_string(), CommandSeparator::SemiColon));
-                current.clear();
+    fn push_char(&mut self, c: char, quoted: bool) {
+        self.current.push(c);
+        if quoted && matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+        self.started = true;
+    }
+
+    fn push_literal(&mut self, text: &str, quoted: bool) {
+        for c in text.chars() {
+            self.push_char(c, quoted);
+        }
+        self.started = true;
+    }
+
+    fn end_field(&mut self) {
+        // Quoted characters are escaped in the pattern, so only unquoted
+        // ones count as wildcards
+        let pattern = std::mem::take(&mut self.pattern);
+        self.fields.push(Field {
+            text: std::mem::take(&mut self.current),
+            glob: glob::has_wildcards(&pattern),
+            pattern,
+        });
+        self.started = false;
+    }
+
+    /// Add the result of an expansion, splitting it on IFS when unquoted
+    fn push_expansion(&mut self, text: &str, quoted: bool) {
+        if quoted || !self.split {
+            return self.push_literal(text, quoted);
+        }
+
+        let ifs = vars::get("IFS").unwrap_or_else(|| " \t\n".to_string());
+        for c in text.chars() {
+            if !ifs.contains(c) {
+                self.push_char(c, false);
+            } else if self.started {
+                self.end_field();
             }
-            '&' if in_quote.is_none() => {
-                if let Some('&') = chars.peek() {
-                    chars.next();
-                    tokens.push((current.trim().to_string(), CommandSeparator::AndAnd));
-                    current.clear();
-                } else {
-                    tokens.push((current.trim().to_string(), CommandSeparator::Background));
-                    current.clear();
-                }
+        }
+    }
+
+    /// Add `$@`: every positional parameter is a field of its own, even when quoted
+    fn push_all_params(&mut self, quoted: bool) {
+        let params = vars::positional();
+        if !quoted {
+            return self.push_expansion(&params.join(" "), false);
+        }
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.end_field();
             }
-            _ => current.push(c),
+            self.push_literal(param, true);
         }
     }
 
-    if !current.trim().is_empty() {
-        tokens.push((current.trim().to_string(), CommandSeparator::None));
+    fn finish(mut self) -> Vec<Field> {
+        if self.started {
+            self.end_field();
+        }
+        self.fields
     }
+}
+
+/// Status of the last command substitution since it was taken, which is the
+/// status of a command that has no name
+static SUBSTITUTION_STATUS: Mutex<Option<i32>> = Mutex::new(None);
 
-    tokens
+fn set_substitution_status(status: i32) {
+    *SUBSTITUTION_STATUS.lock().unwrap() = Some(status);
+    shell::set_last_status(status);
 }
 
-pub fn expand_braces(input: &str) -> Vec<String> {
-    let mut result = vec![String::new()];
-    let mut stack = vec![];
+fn take_substitution_status() -> Option<i32> {
+    SUBSTITUTION_STATUS.lock().unwrap().take()
+}
 
-    for c in input.chars() {
-        match c {
-            '{' => {
-                stack.push(result);
-                result = vec![String::new()];
-            }
-            '}' => {
-                let parts: Vec<String> = result.iter()
-                    .flat_map(|s| s.split(','))
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                
-                result = stack.pop().unwrap();
-                result = result.iter()
-                    .flat_map(|prev| parts.iter().map(move |p| format!("{}{}", prev, p)))
-                    .collect();
-            }
-            _ => {
-                for s in &mut result {
-                    s.push(c);
-                }
+/// Run a command substitution in a subshell and return what it printed
+fn command_substitution(source: &str) -> String {
+    let list = match parser::parse(source) {
+        Ok(list) => list,
+        Err(e) => {
+            eprintln!("{}", e);
+            set_substitution_status(2);
+            return String::new();
+        }
+    };
+
+    let (mut reader, writer) = match std::io::pipe() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            report(Err(e));
+            return String::new();
+        }
+    };
+    let (read_fd, write_fd) = (reader.as_raw_fd(), writer.as_raw_fd());
+
+    let child = shell::fork_shell(|| {
+        unsafe {
+            libc::close(read_fd);
+            libc::dup2(write_fd, libc::STDOUT_FILENO);
+            libc::close(write_fd);
+        }
+        run_list(&list)
+    });
+    drop(writer);
+
+    let mut bytes = Vec::new();
+    match child {
+        Ok(pid) => {
+            let _ = reader.read_to_end(&mut bytes);
+            set_substitution_status(jobs::wait_pid(pid));
+        }
+        Err(e) => {
+            report(Err(e));
+        }
+    }
+
+    let mut output = String::from_utf8_lossy(&bytes).into_owned();
+    output.truncate(output.trim_end_matches('\n').len());
+    output
+}
+
+/// Names of parameters that are not variables, like `$?` and `$1`
+fn is_special(name: &str) -> bool {
+    matches!(name, "?" | "#" | "@" | "*")
+        || (!name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn lookup(name: &str) -> Option<String> {
+    match name {
+        "?" => Some(shell::last_status().to_string()),
+        "#" => Some(vars::positional().len().to_string()),
+        "@" | "*" => {
+            // Joined by the first character of IFS, as in "$*"
+            let separator = vars::get("IFS").map_or(" ".to_string(), |ifs| ifs.chars().take(1).collect());
+            let params = vars::positional();
+            (!params.is_empty()).then(|| params.join(&separator))
+        }
+        "0" => Some(vars::script_name()),
+        "PIPESTATUS" => shell::pipe_status().first().map(|s| s.to_string()),
+        _ if is_special(name) => {
+            let index: usize = name.parse().ok()?;
+            // `${00}` is still `$0`
+            match index.checked_sub(1) {
+                Some(index) => vars::positional().get(index).cloned(),
+                None => Some(vars::script_name()),
             }
         }
+        _ => vars::get(name),
     }
-    result
 }
 
-pub fn expand_vars(input: &str) -> String {
+/// Elements of an array: `PIPESTATUS`, or any other variable as an array of one
+fn elements(name: &str) -> Vec<String> {
+    match name {
+        "PIPESTATUS" => shell::pipe_status().iter().map(|s| s.to_string()).collect(),
+        _ => lookup(name).into_iter().collect(),
+    }
+}
+
+/// `${NAME[index]}`, where `@` or `*` stand for all elements and a negative
+/// index counts from the end
+fn element(name: &str, index: &str, body: &str) -> Result<Option<String>> {
+    let elements = elements(name);
+    if index == "@" || index == "*" {
+        return Ok((!elements.is_empty()).then(|| elements.join(" ")));
+    }
+
+    let index: i64 = expand_text(&lexer::parse_word(index)?)?
+        .trim()
+        .parse()
+        .map_err(|_| bad_substitution(body))?;
+    let index = if index < 0 { elements.len() as i64 + index } else { index };
+    Ok(usize::try_from(index).ok().and_then(|i| elements.get(i).cloned()))
+}
+
+fn bad_substitution(body: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("shesh: ${{{}}}: bad substitution", body)
+    )
+}
+
+/// Char boundaries of `s`, including its end
+fn boundaries(s: &str) -> Vec<usize> {
+    s.char_indices().map(|(i, _)| i).chain([s.len()]).collect()
+}
+
+/// `${VAR#pat}` and `${VAR##pat}`
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut cuts = boundaries(value);
+    if longest {
+        cuts.reverse();
+    }
+    cuts.into_iter()
+        .find(|&i| glob::matches(pattern, &value[..i]))
+        .map_or_else(|| value.to_string(), |i| value[i..].to_string())
+}
+
+/// `${VAR%pat}` and `${VAR%%pat}`
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut cuts = boundaries(value);
+    if !longest {
+        cuts.reverse();
+    }
+    cuts.into_iter()
+        .find(|&i| glob::matches(pattern, &value[i..]))
+        .map_or_else(|| value.to_string(), |i| value[..i].to_string())
+}
+
+/// `${VAR/pat/rep}` and `${VAR//pat/rep}`, replacing the longest match at each position
+fn replace_pattern(value: &str, pattern: &str, replacement: &str, all: bool) -> String {
+    let cuts = boundaries(value);
     let mut result = String::new();
-    let mut var_name = String::new();
-    let mut in_var = false;
+    let mut pos = 0;
 
-    for c in input.chars() {
-        match c {
-            '$' if !in_var => in_var = true,
-            c if in_var => {
-                if c.is_alphanumeric() || c == '_' {
-                    var_name.push(c);
-                } else {
-                    result.push_str(&std::env::var(&var_name).unwrap_or_default());
-                    var_name.clear();
-                    in_var = false;
-                    result.push(c);
+    while pos < cuts.len() {
+        let start = cuts[pos];
+        let found = cuts[pos + 1..].iter().rev()
+            .position(|&end| glob::matches(pattern, &value[start..end]))
+            .map(|i| cuts.len() - 1 - i);
+
+        match found {
+            Some(end) => {
+                result.push_str(replacement);
+                if !all {
+                    result.push_str(&value[cuts[end]..]);
+                    return result;
                 }
+                pos = end;
             }
-            _ => {
-                if in_var {
-                    result.push_str(&std::env::var(&var_name).unwrap_or_default());
-                    var_name.clear();
-                    in_var = false;
-                }
-                result.push(c);
+            None => {
+                result.push_str(&value[start..cuts.get(pos + 1).copied().unwrap_or(start)]);
+                pos += 1;
             }
         }
     }
-
-    if in_var {
-        result.push_str(&std::env::var(&var_name).unwrap_or_default());
-    }
-
     result
 }
 
-fn wildcard_match(name: &str, pattern: &str) -> bool {
-    let pattern = pattern.trim();
-    if pattern == "*" {
-        return true;
+/// Expand the body of `${...}`
+fn expand_braced(body: &str) -> Result<String> {
+    // ${#NAME[@]} is the number of elements
+    if let Some(name) = body.strip_prefix('#')
+        .and_then(|b| b.strip_suffix("[@]").or_else(|| b.strip_suffix("[*]")))
+        && vars::is_valid_name(name) {
+        return Ok(elements(name).len().to_string());
     }
-    
-    if let Some(suffix) = pattern.strip_prefix('*') {
-        return name.ends_with(suffix);
+
+    // ${#VAR} is the length of the value
+    if let Some(name) = body.strip_prefix('#')
+        && (is_special(name) || vars::is_valid_name(name)) {
+        return Ok(lookup(name).unwrap_or_default().chars().count().to_string());
+    }
+
+    let name_len = match body.chars().next() {
+        Some('?' | '#' | '@' | '*') => 1,
+        Some(c) if c.is_ascii_digit() => {
+            body.find(|c: char| !c.is_ascii_digit()).unwrap_or(body.len())
+        }
+        _ => body.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(body.len()),
+    };
+    let (name, rest) = body.split_at(name_len);
+    if name.is_empty() {
+        return Err(bad_substitution(body));
     }
-    
-    if let Some(prefix) = pattern.strip_suffix('*') {
-        return name.starts_with(prefix);
+    let (value, rest) = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+        Some((index, rest)) => (element(name, index, body)?, rest),
+        None => (lookup(name), rest),
+    };
+    if rest.is_empty() {
+        return Ok(value.unwrap_or_default());
     }
-    
-    name == pattern
-}
 
-pub fn expand_wildcard(pattern: &str) -> Vec<String> {
-    let pattern = pattern.trim();
-    let dir = std::path::Path::new(".");
-    
-    let mut matches = Vec::new();
-    if let Ok(entries) = dir.read_dir() {
-        for entry in entries.flatten() {
-            let name = entry.file_name().to_string_lossy().into_owned();
-            if wildcard_match(&name, pattern) {
-                matches.push(name);
+    const OPERATORS: [&str; 14] = [
+        ":-", ":=", ":+", ":?", "-", "=", "+", "?", "##", "#", "%%", "%", "//", "/",
+    ];
+    let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+        return Err(bad_substitution(body));
+    };
+    let operand = &rest[op.len()..];
+    let expand_operand = |text: &str| expand_text(&lexer::parse_word(text)?);
+    let expand_operand_pattern = |text: &str| expand_pattern(&lexer::parse_word(text)?);
+
+    // With a colon an empty value counts as missing, without one only unset does
+    let missing = if op.starts_with(':') {
+        value.as_deref().is_none_or(str::is_empty)
+    } else {
+        value.is_none()
+    };
+    let value = value.unwrap_or_default();
+
+    Ok(match *op {
+        ":-" | "-" if missing => expand_operand(operand)?,
+        ":=" | "=" if missing => {
+            if !vars::is_valid_name(name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("shesh: ${}: cannot assign in this way", name)
+                ));
             }
+            let assigned = expand_operand(operand)?;
+            vars::set(name, &assigned);
+            assigned
         }
-    }
-    matches
+        ":+" | "+" if missing => String::new(),
+        ":+" | "+" => expand_operand(operand)?,
+        ":?" | "?" if missing => {
+            let message = match expand_operand(operand)? {
+                m if m.is_empty() => "parameter null or not set".to_string(),
+                m => m,
+            };
+            return Err(Error::new(UNSET_PARAMETER, format!("shesh: {}: {}", name, message)));
+        }
+        "#" => remove_prefix(&value, &expand_operand_pattern(operand)?, false),
+        "##" => remove_prefix(&value, &expand_operand_pattern(operand)?, true),
+        "%" => remove_suffix(&value, &expand_operand_pattern(operand)?, false),
+        "%%" => remove_suffix(&value, &expand_operand_pattern(operand)?, true),
+        "/" | "//" => {
+            let (pattern, replacement) = operand.split_once('/').unwrap_or((operand, ""));
+            replace_pattern(
+                &value,
+                &expand_operand_pattern(pattern)?,
+                &expand_operand(replacement)?,
+                *op == "//",
+            )
+        }
+        _ => value,
+    })
 }
 
-pub fn parse_redirects(input: &str) -> ParsedCommand {
-    let mut parts = input.split_whitespace();
-    let mut cmd = Vec::new();
-    let mut redirects = Vec::new();
+/// Expand `$NAME`, `${...}`, `$?`, `$(...)` and backquotes in the text of one word part
+fn expand_dollars(text: &str, quoted: bool, fields: &mut Fields) -> Result<()> {
+    let mut chars = text.chars().peekable();
 
-    while let Some(part) = parts.next() {
-        match part {
-            ">" => {
-                if let Some(file) = parts.next() {
-                    redirects.push(Redirect::Output(file.to_string()));
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => match chars.peek() {
+                Some('$' | '`' | '"' | '\\') => {
+                    let next = chars.next().unwrap();
+                    fields.push_char(next, true);
                 }
-            },
-            ">>" => {
-                if let Some(file) = parts.next() {
-                    redirects.push(Redirect::Append(file.to_string()));
+                Some('\n') => {
+                    chars.next();
                 }
+                _ => fields.push_char('\\', true),
             },
-            "<" => {
-                if let Some(file) = parts.next() {
-                    redirects.push(Redirect::Input(file.to_string()));
+            '`' => {
+                let body = lexer::read_backquoted(&mut chars)?;
+                let output = command_substitution(&lexer::unescape_backquoted(&body));
+                fields.push_expansion(&output, quoted);
+            }
+            '$' => match chars.peek() {
+                Some('(') => {
+                    chars.next();
+                    let body = lexer::read_paren_body(&mut chars)?;
+                    fields.push_expansion(&command_substitution(&body), quoted);
+                }
+                Some('{') => {
+                    chars.next();
+                    match lexer::read_brace_body(&mut chars)?.as_str() {
+                        "@" => fields.push_all_params(quoted),
+                        body => fields.push_expansion(&expand_braced(body)?, quoted),
+                    }
+                }
+                Some('@') => {
+                    chars.next();
+                    fields.push_all_params(quoted);
+                }
+                // Special parameters and $0 to $9 are a single character
+                Some(&n) if matches!(n, '?' | '#' | '*') || n.is_ascii_digit() => {
+                    chars.next();
+                    fields.push_expansion(&lookup(&n.to_string()).unwrap_or_default(), quoted);
                 }
+                Some(&n) if n.is_alphanumeric() || n == '_' => {
+                    let mut var_name = String::new();
+                    while let Some(n) = chars.next_if(|n| n.is_alphanumeric() || *n == '_') {
+                        var_name.push(n);
+                    }
+                    fields.push_expansion(&lookup(&var_name).unwrap_or_default(), quoted);
+                }
+                _ => fields.push_char('$', quoted),
             },
-            _ => cmd.push(part.to_string()),
+            _ => fields.push_char(c, quoted),
+        }
+    }
+    Ok(())
+}
+
+/// Expand a leading unquoted `~` to the home directory
+fn expand_leading_tilde<'a>(text: &'a str, fields: &mut Fields) -> &'a str {
+    match text.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            fields.push_literal(&vars::get("HOME").unwrap_or_else(|| ".".to_string()), true);
+            rest
+        }
+        _ => text,
+    }
+}
+
+/// Expand the tilde and `$` forms of a word. Unquoted expansions are split
+/// into fields, and only unquoted wildcards are left active for globbing.
+fn expand_fields(word: &Word, split: bool) -> Result<Vec<Field>> {
+    let mut fields = Fields::new(split);
+
+    for (i, part) in word.parts.iter().enumerate() {
+        match part {
+            WordPart::Bare(s) if i == 0 => {
+                let rest = expand_leading_tilde(s, &mut fields);
+                expand_dollars(rest, false, &mut fields)?;
+            }
+            WordPart::Bare(s) => expand_dollars(s, false, &mut fields)?,
+            WordPart::DoubleQuoted(s) => {
+                // Quotes always make a field, even an empty one, except that
+                // "$@" makes none when there are no positional parameters
+                if !matches!(s.as_str(), "$@" | "${@}") {
+                    fields.started = true;
+                }
+                expand_dollars(s, true, &mut fields)?;
+            }
+            WordPart::SingleQuoted(s) => fields.push_literal(s, true),
         }
     }
-    ParsedCommand { cmd, redirects }
+    Ok(fields.finish())
+}
+
+/// Expand a word to a single string, as in assignments
+fn expand_text(word: &Word) -> Result<String> {
+    Ok(expand_fields(word, false)?
+        .into_iter()
+        .map(|field| field.text)
+        .collect())
+}
+
+/// Expand a word to a single pattern in which quoted characters match literally
+fn expand_pattern(word: &Word) -> Result<String> {
+    Ok(expand_fields(word, false)?
+        .into_iter()
+        .map(|field| field.pattern)
+        .collect())
 }
 
 pub fn expand_tilde(path: &str) -> String {
     if path.starts_with("~/") {
-        let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        let home = vars::get("HOME").unwrap_or_else(|| ".".to_string());
         path.replacen('~', &home, 1)
     } else if path == "~" {
-        env::var("HOME").unwrap_or_else(|_| ".".to_string())
+        vars::get("HOME").unwrap_or_else(|| ".".to_string())
     } else {
         path.to_string()
     }
 }
 
-pub fn parse_pipeline(input: &str) -> Vec<Vec<String>> {
-    input.split('|')
-        .map(|part| part.split_whitespace().map(|s| s.to_string()).collect())
-        .collect()
-}
-
 /// Complete command names based on input prefix
 pub fn complete_command(prefix: &str) -> Vec<String> {
-    let path = env::var("PATH").unwrap_or_default();
+    let path = vars::get("PATH").unwrap_or_default();
     let mut completions = Vec::new();
 
     for dir in path.split(':') {
         if let Ok(entries) = fs::read_dir(dir) {
             for entry in entries.flatten() {
-                if let Ok(file_name) = entry.file_name().into_string() {
-                    if file_name.starts_with(prefix) {
-                        completions.push(file_name);
-                    }
+                if let Ok(file_name) = entry.file_name().into_string()
+                    && file_name.starts_with(prefix) {
+                    completions.push(file_name);
                 }
             }
         }
@@ -296,15 +723,14 @@ pub fn complete_path(prefix: &str) -> Vec<String> {
 
     if let Ok(entries) = fs::read_dir(parent) {
         for entry in entries.flatten() {
-            if let Ok(file_name) = entry.file_name().into_string() {
-                if file_name.starts_with(file_stem) {
-                    let mut full_path = entry.path();
-                    if full_path.is_dir() {
-                        full_path.push("");
-                    }
-                    if let Some(full_str) = full_path.to_str() {
-                        completions.push(full_str.to_string());
-                    }
+            if let Ok(file_name) = entry.file_name().into_string()
+                && file_name.starts_with(file_stem) {
+                let mut full_path = entry.path();
+                if full_path.is_dir() {
+                    full_path.push("");
+                }
+                if let Some(full_str) = full_path.to_str() {
+                    completions.push(full_str.to_string());
                 }
             }
         }
@@ -313,67 +739,578 @@ pub fn complete_path(prefix: &str) -> Vec<String> {
     completions
 }
 
-// Main command processing
-pub fn process_command(cmd_str: &str, background: bool) -> bool {
-    // Step 1: Command expansion
-    let expanded = {
-        let step1 = expand_vars(cmd_str);
-        let step2 = expand_tilde(&step1);
-        parse_input(&step2)
-            .iter()
-            .flat_map(|p| expand_braces(p))
-            .flat_map(|p| {
-                if p.contains('*') {
-                    expand_wildcard(&p)
-                } else {
-                    vec![p]
+/// Expand a single word into zero or more arguments
+fn expand_word(word: &Word) -> Result<Vec<String>> {
+    let mut result = Vec::new();
+
+    for word in expand_braces(word) {
+        for field in expand_fields(&word, true)? {
+            if !field.glob {
+                result.push(field.text);
+                continue;
+            }
+            let matches = glob::expand(&field.pattern);
+            if !matches.is_empty() {
+                result.extend(matches);
+            } else if options::is_set("failglob") {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("shesh: no match: {}", field.text)
+                ));
+            } else {
+                result.push(field.text);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Expand every word of a command into its argument list
+fn expand_words(words: &[Word]) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for word in words {
+        expanded.extend(expand_word(word)?);
+    }
+    Ok(expanded)
+}
+
+/// Expand the file or fd a redirection points at, which must be a single field
+fn redirect_target(word: &Word) -> Result<String> {
+    let mut fields = expand_word(word)?;
+    if fields.len() != 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("shesh: {}: ambiguous redirect", word.text())
+        ));
+    }
+    Ok(fields.remove(0))
+}
+
+fn expand_redirect(node: &RedirectNode) -> Result<Vec<Redirect>> {
+    let fd = node.fd;
+    Ok(match node.op {
+        RedirectOp::Output => vec![Redirect::Output(fd, redirect_target(&node.target)?)],
+        RedirectOp::Append => vec![Redirect::Append(fd, redirect_target(&node.target)?)],
+        RedirectOp::Input => vec![Redirect::Input(fd, redirect_target(&node.target)?)],
+        RedirectOp::OutputBoth => {
+            vec![Redirect::Output(1, redirect_target(&node.target)?), Redirect::Dup(2, 1)]
+        }
+        RedirectOp::AppendBoth => {
+            vec![Redirect::Append(1, redirect_target(&node.target)?), Redirect::Dup(2, 1)]
+        }
+        RedirectOp::DupOutput | RedirectOp::DupInput => {
+            let target = redirect_target(&node.target)?;
+            match target.parse() {
+                Ok(source) => vec![Redirect::Dup(fd, source)],
+                _ if target == "-" => vec![Redirect::Close(fd)],
+                // `>&file` is another way to write `&>file`
+                _ if node.op == RedirectOp::DupOutput && fd == 1 => {
+                    vec![Redirect::Output(1, target), Redirect::Dup(2, 1)]
                 }
-            })
-            .collect::<Vec<String>>()
-    };
+                _ => return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("shesh: {}: ambiguous redirect", target)
+                )),
+            }
+        }
+        // Here-documents and here-strings are neither split nor globbed
+        RedirectOp::HereDoc => vec![Redirect::Text(fd, expand_text(&node.target)?)],
+        RedirectOp::HereString => vec![Redirect::Text(fd, expand_text(&node.target)? + "\n")],
+    })
+}
 
-    let parts: Vec<&str> = expanded.iter().map(|s| s.as_str()).collect();
-    let joined_input = expanded.join(" ");
+/// A simple command after expansion
+struct Expanded {
+    assignments: Vec<(String, String)>,
+    argv: Vec<String>,
+    redirects: Vec<Redirect>,
+}
 
-    // Step 2: Handle pipelines
-    if parts.contains(&"|") {
-        let commands = parse_pipeline(&joined_input);
-        return if background {
-            shell::execute_background_pipeline(commands).is_ok()
+fn expand_redirects(redirects: &[RedirectNode]) -> Result<Vec<Redirect>> {
+    Ok(redirects.iter()
+        .map(expand_redirect)
+        .collect::<Result<Vec<_>>>()?
+        .concat())
+}
+
+fn expand_command(cmd: &SimpleCommand) -> Result<Expanded> {
+    let mut assignments = Vec::new();
+    for (name, value) in &cmd.assignments {
+        assignments.push((name.clone(), expand_text(value)?));
+    }
+
+    Ok(Expanded {
+        assignments,
+        argv: expand_words(&cmd.words)?,
+        redirects: expand_redirects(&cmd.redirects)?,
+    })
+}
+
+/// Expand a pipeline stage. A compound command only has its redirections
+/// expanded here, and its description stands in for the argv in job listings.
+fn expand_stage(command: &Command) -> Result<Expanded> {
+    match command {
+        Command::Simple(cmd) => expand_command(cmd),
+        Command::Compound(compound, redirects) => Ok(Expanded {
+            assignments: Vec::new(),
+            argv: vec![compound.describe().to_string()],
+            redirects: expand_redirects(redirects)?,
+        }),
+        Command::Function(name, _) => Ok(Expanded {
+            assignments: Vec::new(),
+            argv: vec![name.clone()],
+            redirects: Vec::new(),
+        }),
+    }
+}
+
+/// The kind of error `${VAR:?msg}` gives, which ends a shell that is not interactive
+const UNSET_PARAMETER: ErrorKind = ErrorKind::Other;
+
+fn print_error(e: &Error) {
+    eprintln!("{}", e);
+    if e.kind() == UNSET_PARAMETER && !jobs::enabled() {
+        std::process::exit(1);
+    }
+}
+
+/// Turn the outcome of a shell operation into an exit status, reporting errors
+fn report(result: Result<i32>) -> i32 {
+    result.unwrap_or_else(|e| {
+        print_error(&e);
+        1
+    })
+}
+
+/// Run every and-or list in order, returning the status of the last one
+pub fn run_list(list: &List) -> i32 {
+    let mut status = 0;
+
+    for (and_or, separator) in &list.items {
+        let background = *separator == CommandSeparator::Background;
+        status = if background && !and_or.rest.is_empty() {
+            run_and_or_background(and_or)
         } else {
-            shell::execute_pipeline(commands).is_ok()
+            run_and_or(and_or, background)
         };
+        if shell::flow().is_some() {
+            break;
+        }
     }
+    status
+}
 
-    // Step 3: Handle redirections
-    let parsed = parse_redirects(&joined_input);
-    if !parsed.redirects.is_empty() {
-        return if background {
-            shell::execute_background_with_redirect(&parsed.cmd, &parsed.redirects).is_ok()
-        } else {
-            shell::execute_with_redirect(&parsed.cmd, &parsed.redirects).is_ok()
+fn run_and_or(and_or: &AndOr, background: bool) -> i32 {
+    let mut status = run_pipeline(&and_or.first, background);
+
+    for (separator, pipeline) in &and_or.rest {
+        if shell::flow().is_some() {
+            break;
+        }
+        match separator {
+            // Skip this command because previous failed
+            CommandSeparator::AndAnd if status != 0 => continue,
+            // Skip this command because previous succeeded
+            CommandSeparator::OrOr if status == 0 => continue,
+            _ => {}
+        }
+        status = run_pipeline(pipeline, background);
+    }
+    status
+}
+
+/// Run a whole and-or list as one background job, so that its pipelines
+/// still run one after another and see each other's status
+fn run_and_or_background(and_or: &AndOr) -> i32 {
+    let argv = [and_or.describe()];
+    let stage = Stage {
+        argv: &argv,
+        assignments: &[],
+        redirects: &[],
+        run: Some(Box::new(|| run_and_or(and_or, false))),
+    };
+    let status = report(shell::execute_background_pipeline(vec![stage]).map(|_| 0));
+    shell::set_last_status(status);
+    status
+}
+
+fn run_pipeline(pipeline: &Pipeline, background: bool) -> i32 {
+    let statuses = match pipeline.commands.as_slice() {
+        [Command::Simple(cmd)] => vec![process_command(cmd, background)],
+        // Compound commands run in the shell itself unless they go to the background
+        [Command::Compound(compound, redirects)] if !background => {
+            vec![run_compound_command(compound, redirects)]
+        }
+        [Command::Function(name, body)] if !background => {
+            functions::define(name, body.clone());
+            vec![0]
+        }
+        commands => run_stages(commands, background),
+    };
+
+    let status = shell::pipeline_status(&statuses);
+    shell::set_pipe_status(statuses);
+    shell::set_last_status(status);
+    status
+}
+
+/// Run the commands of a pipeline together, returning the status of each
+fn run_stages(commands: &[Command], background: bool) -> Vec<i32> {
+    let expanded = match commands.iter()
+        .map(expand_stage)
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(expanded) => expanded,
+        Err(e) => return vec![report(Err(e))],
+    };
+
+    let stages = commands.iter().zip(&expanded).map(|(command, cmd)| {
+        // Compound commands, functions, builtins and bare redirections are
+        // run by a forked copy of the shell
+        let run: Option<Box<dyn FnOnce() -> i32 + '_>> = match command {
+            Command::Compound(compound, _) => Some(Box::new(|| run_compound(compound))),
+            Command::Function(name, body) => Some(Box::new(|| {
+                functions::define(name, body.clone());
+                0
+            })),
+            Command::Simple(_) if cmd.argv.first().is_none_or(|name| {
+                builtins::is_builtin(name) || functions::is_defined(name)
+            }) => {
+                Some(Box::new(|| {
+                    vars::with_assignments(&cmd.assignments, || run_command(&cmd.argv, &[], false))
+                }))
+            }
+            Command::Simple(_) => None,
         };
+        Stage {
+            argv: &cmd.argv,
+            assignments: &cmd.assignments,
+            redirects: &cmd.redirects,
+            run,
+        }
+    }).collect();
+
+    if background {
+        vec![report(shell::execute_background_pipeline(stages).map(|_| 0))]
+    } else {
+        shell::execute_pipeline(stages).unwrap_or_else(|e| vec![report(Err(e))])
     }
+}
+
+/// Run a compound command in the shell, with its redirections applied around it
+fn run_compound_command(compound: &CompoundCommand, redirects: &[RedirectNode]) -> i32 {
+    let redirects = match expand_redirects(redirects) {
+        Ok(redirects) => redirects,
+        Err(e) => return report(Err(e)),
+    };
+    report(shell::with_redirects(&redirects, || run_compound(compound)))
+}
 
+fn run_compound(compound: &CompoundCommand) -> i32 {
+    match compound {
+        CompoundCommand::If { branches, otherwise } => {
+            for (condition, body) in branches {
+                let status = run_list(condition);
+                if shell::flow().is_some() {
+                    return status;
+                }
+                if status == 0 {
+                    return run_list(body);
+                }
+            }
+            // No branch taken and no `else` is a success
+            otherwise.as_ref().map_or(0, run_list)
+        }
+        CompoundCommand::Group(list) => run_list(list),
+        CompoundCommand::Conditional(expr) => match eval_condition(expr) {
+            Ok(true) => 0,
+            Ok(false) => 1,
+            Err(e) => {
+                print_error(&e);
+                2
+            }
+        },
+        CompoundCommand::For { name, words, body } => {
+            let items = match words {
+                Some(words) => match expand_words(words) {
+                    Ok(items) => items,
+                    Err(e) => return report(Err(e)),
+                },
+                None => vars::positional(),
+            };
+
+            shell::in_loop(|| {
+                let mut status = 0;
+                for item in items {
+                    vars::set(name, &item);
+                    status = run_list(body);
+                    if loop_should_stop() {
+                        break;
+                    }
+                }
+                status
+            })
+        }
+        CompoundCommand::While { condition, body, until } => shell::in_loop(|| {
+            let mut status = 0;
+            loop {
+                let tested = run_list(condition);
+                if shell::flow().is_some() {
+                    if loop_should_stop() {
+                        break;
+                    }
+                    continue;
+                }
+                if (tested == 0) == *until {
+                    break;
+                }
+                status = run_list(body);
+                if loop_should_stop() {
+                    break;
+                }
+            }
+            status
+        }),
+        CompoundCommand::Case { word, items } => {
+            let text = match expand_text(word) {
+                Ok(text) => text,
+                Err(e) => return report(Err(e)),
+            };
+
+            let mut status = 0;
+            let mut falling = false;
+            for item in items {
+                if !falling {
+                    let mut matched = false;
+                    for pattern in &item.patterns {
+                        match expand_pattern(pattern) {
+                            Ok(pattern) if glob::matches(&pattern, &text) => {
+                                matched = true;
+                                break;
+                            }
+                            Ok(_) => {}
+                            Err(e) => return report(Err(e)),
+                        }
+                    }
+                    if !matched {
+                        continue;
+                    }
+                }
+
+                status = run_list(&item.body);
+                if shell::flow().is_some() {
+                    break;
+                }
+                match item.end {
+                    CaseEnd::Break => break,
+                    CaseEnd::FallThrough => falling = true,
+                    CaseEnd::Continue => falling = false,
+                }
+            }
+            status
+        }
+    }
+}
+
+/// Handle a `break` or `continue` that reached the loop being run, passing
+/// on what is left of one aimed further out. Returns whether the loop ends.
+fn loop_should_stop() -> bool {
+    match shell::take_flow() {
+        None | Some(Flow::Continue(1)) => false,
+        // A `return` or Ctrl-C leaves every loop on its way out
+        Some(flow @ (Flow::Return | Flow::Interrupt)) => {
+            shell::set_flow(Some(flow));
+            true
+        }
+        Some(Flow::Break(1)) => true,
+        Some(Flow::Break(n)) => {
+            shell::set_flow(Some(Flow::Break(n - 1)));
+            true
+        }
+        Some(Flow::Continue(n)) => {
+            shell::set_flow(Some(Flow::Continue(n - 1)));
+            true
+        }
+    }
+}
+
+/// Expand the right side of `=~`. Quoted parts match literally, so their
+/// special characters are escaped for the regular expression.
+fn expand_regex(word: &Word) -> Result<String> {
+    let mut regex = String::new();
+    for part in &word.parts {
+        let text = expand_text(&Word { parts: vec![part.clone()] })?;
+        if let WordPart::Bare(_) = part {
+            regex.push_str(&text);
+            continue;
+        }
+        for c in text.chars() {
+            if "\\^$.|?*+()[]{}".contains(c) {
+                regex.push('\\');
+            }
+            regex.push(c);
+        }
+    }
+    Ok(regex)
+}
+
+/// Evaluate `[[ ]]`, whose words are expanded without splitting or globbing
+fn eval_condition(expr: &CondExpr) -> Result<bool> {
+    Ok(match expr {
+        CondExpr::Not(expr) => !eval_condition(expr)?,
+        CondExpr::And(left, right) => eval_condition(left)? && eval_condition(right)?,
+        CondExpr::Or(left, right) => eval_condition(left)? || eval_condition(right)?,
+        CondExpr::Word(word) => !expand_text(word)?.is_empty(),
+        CondExpr::Unary(op, word) => condition::unary(op, &expand_text(word)?)?,
+        CondExpr::Binary(left, op, right) => {
+            let left = expand_text(left)?;
+            match op.as_str() {
+                // The right side is a pattern unless it is quoted
+                "=" | "==" => glob::matches(&expand_pattern(right)?, &left),
+                "!=" => !glob::matches(&expand_pattern(right)?, &left),
+                "=~" => condition::regex_matches(&expand_regex(right)?, &left)?,
+                _ => condition::binary(&left, op, &expand_text(right)?)?,
+            }
+        }
+    })
+}
+
+/// Call a function with `args` as its positional parameters
+fn call_function(body: &FunctionBody, args: &[String]) -> i32 {
+    let saved = vars::set_positional(args.to_vec());
+    let status = shell::in_call(|| {
+        vars::with_scope(|| run_compound_command(&body.command, &body.redirects))
+    });
+    vars::set_positional(saved);
+    status
+}
+
+/// Apply the redirections of a command without a name. Its status is that of
+/// its last command substitution, or 0 if it had none.
+fn without_command(redirects: &[Redirect]) -> i32 {
+    let status = take_substitution_status().unwrap_or(0);
+    match shell::execute_with_redirect(&[], redirects) {
+        Ok(_) => status,
+        Err(e) => report(Err(e)),
+    }
+}
+
+// Main command processing
+pub fn process_command(cmd: &SimpleCommand, background: bool) -> i32 {
+    // Assignments alone are expanded and set one at a time, so that later
+    // values can use earlier ones
+    if cmd.words.is_empty() {
+        take_substitution_status();
+        for (name, value) in &cmd.assignments {
+            match expand_text(value) {
+                Ok(value) => vars::set(name, &value),
+                Err(e) => return report(Err(e)),
+            }
+        }
+        return match expand_redirects(&cmd.redirects) {
+            Ok(redirects) => without_command(&redirects),
+            Err(e) => report(Err(e)),
+        };
+    }
+
+    // Step 1: Word expansion
+    take_substitution_status();
+    let Expanded { assignments, argv: expanded, redirects } = match expand_command(cmd) {
+        Ok(expanded) => expanded,
+        Err(e) => return report(Err(e)),
+    };
+
+    // Step 2: Assignments before words that expand to nothing set shell variables
+    if expanded.is_empty() {
+        for (name, value) in &assignments {
+            vars::set(name, value);
+        }
+        return without_command(&redirects);
+    }
+
+    // Otherwise they only apply to the environment of this command
+    vars::with_assignments(&assignments, || run_command(&expanded, &redirects, background))
+}
+
+fn run_command(expanded: &[String], redirects: &[Redirect], background: bool) -> i32 {
+    let parts: Vec<&str> = expanded.iter().map(|s| s.as_str()).collect();
     let (cmd, args) = match parts.split_first() {
         Some((c, a)) => (c, a),
-        None => return false,
+        None => return 0,
     };
 
-    // Step 4: Built-in commands
-    if let Some(result) = builtins::handle_command(cmd, args) {
-        return if let Err(e) = result {
-            eprintln!("{}", e);
-            false
+    // Step 3: Built-in commands, with any redirections applied to the shell around them
+    if builtins::is_builtin(cmd) {
+        return report(shell::with_redirects(redirects, || {
+            builtins::handle_command(cmd, args).map_or(0, report)
+        }));
+    }
+
+    // Step 4: Functions come before commands in PATH
+    if let Some(body) = functions::get(cmd) {
+        let run = || call_function(&body, &expanded[1..]);
+        return if background {
+            let stage = Stage { argv: expanded, assignments: &[], redirects, run: Some(Box::new(run)) };
+            report(shell::execute_background_pipeline(vec![stage]).map(|_| 0))
         } else {
-            true
+            report(shell::with_redirects(redirects, run))
+        };
+    }
+
+    // Step 5: Handle redirections
+    if !redirects.is_empty() {
+        return if background {
+            report(shell::execute_background_with_redirect(expanded, redirects).map(|_| 0))
+        } else {
+            report(shell::execute_with_redirect(expanded, redirects))
         };
     }
 
-    // Step 5: External commands
+    // Step 6: External commands
     if background {
-        shell::execute_background(cmd, args).is_ok()
+        report(shell::execute_background(cmd, args).map(|_| 0))
     } else {
-        shell::execute(cmd, args).is_ok()
+        shell::execute(cmd, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn braces(text: &str) -> Vec<String> {
+        expand_braces(&lexer::parse_word(text).unwrap()).iter().map(Word::text).collect()
+    }
+
+    #[test]
+    fn brace_lists() {
+        assert_eq!(braces("file{,.bak}"), ["file", "file.bak"]);
+        assert_eq!(braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(braces("x{a,{b,c}}y"), ["xay", "xby", "xcy"]);
+        // Without a comma, or quoted, the braces stay as they are
+        assert_eq!(braces("{a}"), ["{a}"]);
+        assert_eq!(braces("'{a,b}'"), ["{a,b}"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(
+            expand_sequence("01..10").unwrap(),
+            ["01", "02", "03", "04", "05", "06", "07", "08", "09", "10"]
+        );
+        assert_eq!(expand_sequence("3..1").unwrap(), ["3", "2", "1"]);
+        assert_eq!(expand_sequence("0..10..5").unwrap(), ["0", "5", "10"]);
+        assert_eq!(expand_sequence("a..e..2").unwrap(), ["a", "c", "e"]);
+        assert_eq!(expand_sequence("a..3"), None);
+        assert_eq!(braces("v{1..3}"), ["v1", "v2", "v3"]);
+    }
+
+    #[test]
+    fn overlong_sequences_stay_literal() {
+        assert_eq!(expand_sequence("1..100000").unwrap().len(), 100_000);
+        assert_eq!(expand_sequence("1..100001"), None);
+        let extremes = format!("{}..{}", i64::MIN, i64::MAX);
+        assert_eq!(expand_sequence(&extremes), None);
+        assert_eq!(braces("{1..100001}"), ["{1..100001}"]);
     }
 }
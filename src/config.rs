@@ -1,5 +1,7 @@
 use std::{env, fs, io::Write, path::PathBuf};
 
+use crate::script;
+
 pub struct Config {
     pub prompt: String,
     pub startup: Vec<String>,
@@ -59,6 +61,13 @@ fn load_config(path: &PathBuf) -> Config {
     let mut in_startup = false;
     
     for line in content.lines() {
+        // Startup lines are kept as written, since a here-document can
+        // depend on blank lines and indentation
+        if in_startup {
+            config.startup.push(line.to_string());
+            continue;
+        }
+
         let trimmed = line.trim();
         
         if trimmed.is_empty() {
@@ -72,12 +81,9 @@ fn load_config(path: &PathBuf) -> Config {
             continue;
         }
         
-        if in_startup {
-            config.startup.push(trimmed.to_string());
-        } else if let Some((key, value)) = trimmed.split_once('=') {
-            if key.trim() == "prompt" {
-                config.prompt = value.trim().trim_matches('"').to_string();
-            }
+        if let Some((key, value)) = trimmed.split_once('=')
+            && key.trim() == "prompt" {
+            config.prompt = value.trim().trim_matches('"').to_string();
         }
     }
     config
@@ -108,20 +114,6 @@ pub fn load_history() -> Vec<String> {
 }
 
 pub fn run_startup(config: &Config) {
-    use crate::{commands, shell, builtins};
-    
-    for cmd_line in &config.startup {
-        let parts = commands::parse_input(cmd_line);
-        if let Some((cmd, args_vec)) = parts.split_first() {
-            let args: Vec<&str> = args_vec.iter().map(|s| s.as_str()).collect();
-            
-            if let Some(res) = builtins::handle_command(cmd, &args) {
-                if let Err(e) = res {
-                    eprintln!("Startup builtin failed: {}", e);
-                }
-            } else if let Err(e) = shell::execute(cmd, &args) {
-                eprintln!("Startup command failed: {}", e);
-            }
-        }
-    }
+    // Commands such as here-documents may continue over several lines
+    script::run_lines("startup", config.startup.iter().cloned());
 }
@@ -0,0 +1,29 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use crate::parser::FunctionBody;
+
+static FUNCTIONS: Mutex<BTreeMap<String, Arc<FunctionBody>>> = Mutex::new(BTreeMap::new());
+
+/// Define a function, replacing any earlier one with the same name
+pub fn define(name: &str, body: Arc<FunctionBody>) {
+    FUNCTIONS.lock().unwrap().insert(name.to_string(), body);
+}
+
+pub fn get(name: &str) -> Option<Arc<FunctionBody>> {
+    FUNCTIONS.lock().unwrap().get(name).cloned()
+}
+
+pub fn is_defined(name: &str) -> bool {
+    FUNCTIONS.lock().unwrap().contains_key(name)
+}
+
+pub fn unset(name: &str) {
+    FUNCTIONS.lock().unwrap().remove(name);
+}
+
+/// Names of the defined functions, sorted
+pub fn names() -> Vec<String> {
+    FUNCTIONS.lock().unwrap().keys().cloned().collect()
+}
@@ -0,0 +1,263 @@
+use std::{fs, path::Path};
+
+/// Whether a pattern contains an unescaped `*` or `?`, or a bracket
+/// expression that is closed; a lone `[` is an ordinary character
+pub fn has_wildcards(pattern: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < p.len() {
+        match p[i] {
+            '\\' => i += 1,
+            '*' | '?' => return true,
+            '[' if match_class(&p[i..], '\0').is_some() => return true,
+            _ => {}
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Remove the backslashes that protect characters in a pattern
+pub fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Parse a class name like `alpha:]` that follows `[:` in a bracket
+/// expression, returning its length and whether `c` belongs to the class
+fn named_class(p: &[char], c: char) -> Option<(usize, bool)> {
+    let end = p.windows(2).position(|w| w == [':', ']'])?;
+    let matched = match p[..end].iter().collect::<String>().as_str() {
+        "alpha" => c.is_alphabetic(),
+        "digit" => c.is_ascii_digit(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "lower" => c.is_lowercase(),
+        "alnum" => c.is_alphanumeric(),
+        _ => return None,
+    };
+    Some((end + 2, matched))
+}
+
+/// Parse the bracket expression at the start of `p`, returning its length
+/// and whether `c` is one of the characters it lists
+fn match_class(p: &[char], c: char) -> Option<(usize, bool)> {
+    let mut i = 1;
+    let negate = matches!(p.get(i), Some('!' | '^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *p.get(i)?;
+        if lo == ']' && !first {
+            return Some((i + 1, matched != negate));
+        }
+        first = false;
+        if lo == '[' && p.get(i + 1) == Some(&':')
+            && let Some((len, member)) = named_class(&p[i + 2..], c)
+        {
+            matched |= member;
+            i += 2 + len;
+            continue;
+        }
+        if lo == '\\' {
+            i += 1;
+            lo = *p.get(i)?;
+        }
+        i += 1;
+
+        // A range like a-z, unless the `-` is the last character in the class
+        if p.get(i) == Some(&'-') && p.get(i + 1).is_some_and(|&hi| hi != ']') {
+            let mut hi = p[i + 1];
+            i += 2;
+            if hi == '\\' {
+                hi = *p.get(i)?;
+                i += 1;
+            }
+            matched |= lo <= c && c <= hi;
+        } else {
+            matched |= lo == c;
+        }
+    }
+}
+
+/// If the pattern element at the start of `p` matches `c`, return its length
+fn match_one(p: &[char], c: char) -> Option<usize> {
+    match p[0] {
+        '?' => Some(1),
+        '[' => match match_class(p, c) {
+            Some((len, matched)) => matched.then_some(len),
+            // An unterminated bracket is an ordinary character
+            None => (c == '[').then_some(1),
+        },
+        '\\' if p.len() > 1 => (p[1] == c).then_some(2),
+        pc => (pc == c).then_some(1),
+    }
+}
+
+/// Match `text` against a shell pattern with `*`, `?`, `[...]` and backslash escapes
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Where to resume after the most recent `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi + 1, ti));
+            pi += 1;
+            continue;
+        }
+        if pi < p.len() && let Some(len) = match_one(&p[pi..], t[ti]) {
+            pi += len;
+            ti += 1;
+            continue;
+        }
+        match backtrack {
+            Some((star_pi, star_ti)) => {
+                pi = star_pi;
+                ti = star_ti + 1;
+                backtrack = Some((star_pi, star_ti + 1));
+            }
+            None => return false,
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else if prefix.ends_with('/') {
+        format!("{}{}", prefix, name)
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Names in a directory, skipping hidden ones unless asked for
+fn entries(prefix: &str, hidden: bool) -> Vec<String> {
+    let dir = if prefix.is_empty() { "." } else { prefix };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries.flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| hidden || !name.starts_with('.'))
+        .collect()
+}
+
+fn walk(prefix: &str, components: &[&str], dirs_only: bool, out: &mut Vec<String>) {
+    let Some((component, rest)) = components.split_first() else {
+        if !prefix.is_empty() && (!dirs_only || Path::new(prefix).is_dir()) {
+            out.push(if dirs_only { format!("{}/", prefix) } else { prefix.to_string() });
+        }
+        return;
+    };
+
+    if *component == "**" && rest.is_empty() {
+        // Everything below the prefix, but not the prefix itself
+        for name in entries(prefix, false) {
+            let path = join(prefix, &name);
+            let is_dir = Path::new(&path).is_dir();
+            if !dirs_only || is_dir {
+                out.push(if dirs_only { format!("{}/", path) } else { path.clone() });
+            }
+            if is_dir && !fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+                walk(&path, components, dirs_only, out);
+            }
+        }
+    } else if *component == "**" {
+        // Zero or more directories
+        walk(prefix, rest, dirs_only, out);
+        for name in entries(prefix, false) {
+            let path = join(prefix, &name);
+            if Path::new(&path).is_dir() && !fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+                walk(&path, components, dirs_only, out);
+            }
+        }
+    } else if !has_wildcards(component) {
+        let path = join(prefix, &unescape(component));
+        if fs::symlink_metadata(&path).is_ok() {
+            walk(&path, rest, dirs_only, out);
+        }
+    } else {
+        let hidden = component.starts_with('.');
+        for name in entries(prefix, hidden) {
+            if matches(component, &name) {
+                walk(&join(prefix, &name), rest, dirs_only, out);
+            }
+        }
+    }
+}
+
+/// Expand a pattern into the sorted list of paths it matches
+pub fn expand(pattern: &str) -> Vec<String> {
+    let prefix = if pattern.starts_with('/') { "/" } else { "" };
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+
+    let mut matches = Vec::new();
+    walk(prefix, &components, pattern.ends_with('/'), &mut matches);
+    matches.sort();
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bracket_expressions_accept_named_classes() {
+        assert!(matches("[[:alpha:]]*", "abc"));
+        assert!(!matches("[[:alpha:]]*", "1bc"));
+        assert!(matches("[[:digit:]][[:digit:]]", "42"));
+        assert!(matches("a[[:space:]]b", "a b"));
+        assert!(matches("[[:upper:]][[:lower:]]", "Ab"));
+        assert!(matches("[![:alnum:]]", "-"));
+        assert!(matches("[[:digit:]x-z]", "y"));
+        assert!(!matches("[[:digit:]]", "["));
+        assert!(has_wildcards("[[:digit:]]"));
+    }
+
+    #[test]
+    fn trailing_double_star_matches_files_and_directories() {
+        let root = std::env::temp_dir().join(format!("shesh-glob-{}", std::process::id()));
+        fs::create_dir_all(root.join("src/sub")).unwrap();
+        fs::write(root.join("src/a.rs"), "").unwrap();
+        fs::write(root.join("src/sub/b.rs"), "").unwrap();
+        fs::write(root.join("src/.hidden"), "").unwrap();
+        let root = root.to_str().unwrap();
+
+        let expected = |paths: &[&str]| -> Vec<String> {
+            paths.iter().map(|path| format!("{}/{}", root, path)).collect()
+        };
+        assert_eq!(
+            expand(&format!("{}/**", root)),
+            expected(&["src", "src/a.rs", "src/sub", "src/sub/b.rs"])
+        );
+        assert_eq!(
+            expand(&format!("{}/src/**", root)),
+            expected(&["src/a.rs", "src/sub", "src/sub/b.rs"])
+        );
+        assert_eq!(expand(&format!("{}/src/**/", root)), expected(&["src/sub/"]));
+        assert_eq!(
+            expand(&format!("{}/**/*.rs", root)),
+            expected(&["src/a.rs", "src/sub/b.rs"])
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -9,6 +9,7 @@ use termion::terminal_size;
 use crate::utils::{green, gray};
 use crate::config;
 use crate::commands;
+use crate::vars;
 
 /// Represents the state of the line editor
 struct EditorState {
@@ -69,18 +70,16 @@ impl EditorState {
         let hint = self.get_hint();
         
         // Display hint if not showing completions
-        if !self.show_completions {
-            if let Some(hint) = hint.as_deref() {
-                if hint.starts_with(&self.input) {
-                    let remaining = &hint[self.input.len()..];
-                    let colored = if self.matched_hint {
-                        green(remaining)
-                    } else {
-                        gray(remaining)
-                    };
-                    write!(stdout, "{}", colored).unwrap();
-                }
-            }
+        if !self.show_completions
+            && let Some(hint) = hint.as_deref()
+            && hint.starts_with(&self.input) {
+            let remaining = &hint[self.input.len()..];
+            let colored = if self.matched_hint {
+                green(remaining)
+            } else {
+                gray(remaining)
+            };
+            write!(stdout, "{}", colored).unwrap();
         }
         
         // Calculate cursor position
@@ -185,8 +184,17 @@ impl EditorState {
 /// Read a line with advanced editing capabilities
 pub fn read_line_raw(history: &[String]) -> String {
     let config = config::init();
-    let prompt = config.prompt.clone();
-    
+    edit_line(history, config.prompt.clone()).unwrap_or_default()
+}
+
+/// Read another line of an incomplete command, prompting with `PS2`.
+/// Returns `None` if the user cancels with Ctrl-C.
+pub fn read_continuation() -> Option<String> {
+    let prompt = vars::get("PS2").unwrap_or_else(|| "> ".to_string());
+    edit_line(&[], prompt)
+}
+
+fn edit_line(history: &[String], prompt: String) -> Option<String> {
     let stdin = stdin();
     let mut stdin = stdin.lock().keys();
     let mut stdout = stdout().into_raw_mode().unwrap();
@@ -224,14 +232,12 @@ pub fn read_line_raw(history: &[String]) -> String {
                 state.cursor = state.move_cursor_right();
                 state.matched_hint = false;
             }
-            Key::Backspace => {
-                if state.cursor > 0 {
-                    let prev = state.move_cursor_left();
-                    state.input.replace_range(prev..state.cursor, "");
-                    state.cursor = prev;
-                    state.matched_hint = false;
-                    state.show_completions = false;
-                }
+            Key::Backspace if state.cursor > 0 => {
+                let prev = state.move_cursor_left();
+                state.input.replace_range(prev..state.cursor, "");
+                state.cursor = prev;
+                state.matched_hint = false;
+                state.show_completions = false;
             }
             Key::Left => {
                 state.cursor = state.move_cursor_left();
@@ -240,12 +246,11 @@ pub fn read_line_raw(history: &[String]) -> String {
             }
             Key::Right => {
                 if state.cursor == state.input.len() {
-                    if let Some(h) = state.get_hint() {
-                        if h.starts_with(&state.input) {
-                            state.input = h.clone();
-                            state.cursor = state.input.len();
-                            state.matched_hint = true;
-                        }
+                    if let Some(h) = state.get_hint()
+                        && h.starts_with(&state.input) {
+                        state.input = h.clone();
+                        state.cursor = state.input.len();
+                        state.matched_hint = true;
                     }
                 } else {
                     state.cursor = state.move_cursor_right();
@@ -262,10 +267,9 @@ pub fn read_line_raw(history: &[String]) -> String {
                 if state.history_index.is_none() {
                     state.temp_input = state.input.clone();
                     state.history_index = Some(state.history.len().saturating_sub(1));
-                } else if let Some(i) = state.history_index {
-                    if i > 0 {
-                        state.history_index = Some(i - 1);
-                    }
+                } else if let Some(i) = state.history_index
+                    && i > 0 {
+                    state.history_index = Some(i - 1);
                 }
                 
                 if let Some(i) = state.history_index {
@@ -291,8 +295,7 @@ pub fn read_line_raw(history: &[String]) -> String {
             }
             Key::Ctrl('c') => {
                 write!(stdout, "\r\n").unwrap();
-                state.input.clear();
-                break;
+                return None;
             }
             _ => {}
         }
@@ -300,5 +303,5 @@ pub fn read_line_raw(history: &[String]) -> String {
         state.redraw(&mut stdout);
     }
 
-    state.input
+    Some(state.input)
 }
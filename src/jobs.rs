@@ -0,0 +1,395 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    os::unix::process::CommandExt,
+    process::{Child, Command},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use crate::shell;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done,
+}
+
+/// A pipeline started by the shell, running in its own process group
+pub struct Job {
+    pub id: usize,
+    pub pgid: i32,
+    pub procs: Vec<(i32, Option<i32>)>, // pid and exit status once reaped
+    pub command: String,
+    pub state: JobState,
+    tmodes: Option<libc::termios>,      // terminal modes saved when stopped
+}
+
+static JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SHELL_TMODES: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+const TERMINAL: i32 = libc::STDIN_FILENO;
+
+/// Whether this is the interactive shell with job control, rather than a
+/// script or one of its subshells
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Take control of the terminal so jobs can be moved between foreground and background
+pub fn init() {
+    unsafe {
+        if libc::isatty(TERMINAL) == 0 {
+            return;
+        }
+
+        // Wait until we are in the foreground before grabbing the terminal
+        while libc::tcgetpgrp(TERMINAL) != libc::getpgrp() {
+            libc::kill(-libc::getpgrp(), libc::SIGTTIN);
+        }
+
+        for sig in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            libc::signal(sig, libc::SIG_IGN);
+        }
+
+        let pid = libc::getpid();
+        libc::setpgid(pid, pid);
+        libc::tcsetpgrp(TERMINAL, pid);
+    }
+
+    *SHELL_TMODES.lock().unwrap() = terminal_modes();
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn terminal_modes() -> Option<libc::termios> {
+    let mut tmodes = unsafe { std::mem::zeroed::<libc::termios>() };
+    (unsafe { libc::tcgetattr(TERMINAL, &mut tmodes) } == 0).then_some(tmodes)
+}
+
+fn set_terminal_modes(tmodes: &Option<libc::termios>) {
+    if let Some(tmodes) = tmodes {
+        unsafe { libc::tcsetattr(TERMINAL, libc::TCSADRAIN, tmodes) };
+    }
+}
+
+fn give_terminal(pgid: i32) {
+    unsafe { libc::tcsetpgrp(TERMINAL, pgid) };
+}
+
+/// Move the calling child into the process group `group` (0 for a new one)
+/// and restore the signals the shell ignores
+fn join_group(group: i32, foreground: bool) {
+    unsafe {
+        libc::setpgid(0, group);
+        if foreground {
+            libc::tcsetpgrp(TERMINAL, libc::getpgrp());
+        }
+        for sig in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Start a command as part of the process group `pgid`, or as the leader of a new one
+pub fn spawn(command: &mut Command, pgid: Option<i32>, foreground: bool) -> Result<Child> {
+    if ENABLED.load(Ordering::Relaxed) {
+        let group = pgid.unwrap_or(0);
+        unsafe {
+            command.pre_exec(move || {
+                join_group(group, foreground);
+                Ok(())
+            });
+        }
+    }
+
+    let child = command.spawn().inspect_err(|_| {
+        // The child may have taken the terminal before exec failed
+        if foreground && ENABLED.load(Ordering::Relaxed) {
+            give_terminal(unsafe { libc::getpgrp() });
+        }
+    })?;
+    if ENABLED.load(Ordering::Relaxed) {
+        // Set the group from the parent as well so there is no race with the child
+        let pid = child.id() as i32;
+        unsafe { libc::setpgid(pid, pgid.unwrap_or(pid)) };
+    }
+    Ok(child)
+}
+
+/// Like `spawn`, but run `f` in a forked copy of the shell instead of a program
+pub fn fork(pgid: Option<i32>, foreground: bool, f: impl FnOnce() -> i32) -> Result<i32> {
+    let enabled = ENABLED.load(Ordering::Relaxed);
+    let pid = shell::fork_shell(|| {
+        if enabled {
+            join_group(pgid.unwrap_or(0), foreground);
+        }
+        f()
+    })?;
+    if enabled {
+        unsafe { libc::setpgid(pid, pgid.unwrap_or(pid)) };
+    }
+    Ok(pid)
+}
+
+fn decode_status(raw: i32) -> i32 {
+    if libc::WIFEXITED(raw) {
+        libc::WEXITSTATUS(raw)
+    } else if libc::WIFSIGNALED(raw) {
+        128 + libc::WTERMSIG(raw)
+    } else {
+        1
+    }
+}
+
+/// Block until every process has exited or one of them stops
+fn wait_procs(procs: &mut [(i32, Option<i32>)]) -> bool {
+    for (pid, status) in procs.iter_mut().filter(|(_, s)| s.is_none()) {
+        let mut raw = 0;
+        loop {
+            let ret = unsafe { libc::waitpid(*pid, &mut raw, libc::WUNTRACED) };
+            if ret == -1 && Error::last_os_error().kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            if ret == -1 {
+                // Already reaped elsewhere; nothing more to learn about it
+                *status = Some(0);
+            } else if libc::WIFSTOPPED(raw) {
+                return true;
+            } else {
+                // At the prompt, Ctrl-C stops whatever the shell was running,
+                // not just this command
+                if ENABLED.load(Ordering::Relaxed)
+                    && libc::WIFSIGNALED(raw)
+                    && libc::WTERMSIG(raw) == libc::SIGINT
+                {
+                    shell::set_flow(Some(shell::Flow::Interrupt));
+                }
+                *status = Some(decode_status(raw));
+            }
+            break;
+        }
+    }
+    false
+}
+
+/// Block until a single child exits and return its status
+pub fn wait_pid(pid: i32) -> i32 {
+    let mut procs = [(pid, None)];
+    wait_procs(&mut procs);
+    procs[0].1.unwrap_or(0)
+}
+
+/// Called in a forked copy of the shell: commands it starts stay in its
+/// process group and can be interrupted from the terminal again
+pub fn enter_subshell() {
+    ENABLED.store(false, Ordering::Relaxed);
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+        libc::signal(libc::SIGQUIT, libc::SIG_DFL);
+    }
+}
+
+fn next_id(jobs: &[Job]) -> usize {
+    jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1
+}
+
+fn marker(index: usize, len: usize) -> char {
+    if index + 1 == len {
+        '+'
+    } else if index + 2 == len {
+        '-'
+    } else {
+        ' '
+    }
+}
+
+fn print_job(job: &Job, mark: char, state: &str) {
+    println!("[{}]{}  {:<24}{}", job.id, mark, state, job.command);
+}
+
+/// Wait for a foreground job, keeping it in the job table if it gets stopped.
+/// Returns the exit status of each of its commands.
+fn run_in_foreground(mut job: Job) -> Vec<i32> {
+    let enabled = ENABLED.load(Ordering::Relaxed);
+    if enabled && job.pgid > 0 {
+        give_terminal(job.pgid);
+    }
+
+    let stopped = wait_procs(&mut job.procs);
+
+    if enabled {
+        give_terminal(unsafe { libc::getpgrp() });
+        if stopped {
+            job.tmodes = terminal_modes();
+        }
+        set_terminal_modes(&SHELL_TMODES.lock().unwrap());
+    }
+
+    if stopped {
+        let mut jobs = JOBS.lock().unwrap();
+        if job.id == 0 {
+            job.id = next_id(&jobs);
+        }
+        job.state = JobState::Stopped;
+        println!();
+        print_job(&job, '+', "Stopped");
+        let stopped = vec![128 + libc::SIGTSTP; job.procs.len()];
+        jobs.push(job);
+        return stopped;
+    }
+
+    job.procs.iter().map(|(_, s)| s.unwrap_or(0)).collect()
+}
+
+/// Wait for a freshly started pipeline and return the status of each of its commands
+pub fn wait_foreground(pgid: i32, procs: Vec<(i32, Option<i32>)>, command: String) -> Vec<i32> {
+    run_in_foreground(Job {
+        id: 0,
+        pgid,
+        procs,
+        command,
+        state: JobState::Running,
+        tmodes: None,
+    })
+}
+
+/// Record a pipeline started with `&`, announcing its job number when job
+/// control is on; scripts keep their output to themselves
+pub fn add_background(pgid: i32, procs: Vec<(i32, Option<i32>)>, command: String) {
+    let mut jobs = JOBS.lock().unwrap();
+    let enabled = ENABLED.load(Ordering::Relaxed);
+    if !enabled {
+        // Nothing reports finished jobs without job control, so drop them here
+        update(&mut jobs);
+        jobs.retain(|j| j.state != JobState::Done);
+    }
+
+    let id = next_id(&jobs);
+    if enabled {
+        println!("[{}] {}", id, pgid);
+    }
+    jobs.push(Job {
+        id,
+        pgid,
+        procs,
+        command,
+        state: JobState::Running,
+        tmodes: None,
+    });
+}
+
+/// Collect status changes of background jobs without blocking
+fn update(jobs: &mut [Job]) {
+    for job in jobs.iter_mut() {
+        for (pid, status) in job.procs.iter_mut().filter(|(_, s)| s.is_none()) {
+            let mut raw = 0;
+            let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            let ret = unsafe { libc::waitpid(*pid, &mut raw, flags) };
+            if ret == -1 {
+                *status = Some(0);
+            } else if ret == *pid {
+                if libc::WIFSTOPPED(raw) {
+                    job.state = JobState::Stopped;
+                } else if libc::WIFCONTINUED(raw) {
+                    job.state = JobState::Running;
+                } else {
+                    *status = Some(decode_status(raw));
+                }
+            }
+        }
+
+        if job.procs.iter().all(|(_, s)| s.is_some()) {
+            job.state = JobState::Done;
+        }
+    }
+}
+
+fn done_label(job: &Job) -> String {
+    match job.procs.last().and_then(|(_, s)| *s) {
+        Some(0) | None => "Done".to_string(),
+        Some(code) => format!("Exit {}", code),
+    }
+}
+
+/// Report and forget background jobs that have finished
+pub fn notify() {
+    let mut jobs = JOBS.lock().unwrap();
+    update(&mut jobs);
+
+    let len = jobs.len();
+    for (i, job) in jobs.iter().enumerate() {
+        if job.state == JobState::Done {
+            print_job(job, marker(i, len), &done_label(job));
+        }
+    }
+    jobs.retain(|j| j.state != JobState::Done);
+}
+
+/// The `jobs` builtin
+pub fn list() -> Result<()> {
+    let mut jobs = JOBS.lock().unwrap();
+    update(&mut jobs);
+
+    let len = jobs.len();
+    for (i, job) in jobs.iter().enumerate() {
+        let state = match job.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done => done_label(job),
+        };
+        print_job(job, marker(i, len), &state);
+    }
+    jobs.retain(|j| j.state != JobState::Done);
+    Ok(())
+}
+
+/// Find the table index of a job from a `%n`, `%+` or `%-` spec
+fn find(jobs: &[Job], builtin: &str, spec: Option<&str>) -> Result<usize> {
+    let index = match spec.map(|s| s.strip_prefix('%').unwrap_or(s)) {
+        None | Some("" | "%" | "+") => jobs.len().checked_sub(1),
+        Some("-") => jobs.len().checked_sub(2),
+        Some(n) => n.parse().ok().and_then(|id: usize| jobs.iter().position(|j| j.id == id)),
+    };
+
+    index.ok_or_else(|| Error::new(
+        ErrorKind::NotFound,
+        format!("shesh: {}: {}: no such job", builtin, spec.unwrap_or("current"))
+    ))
+}
+
+fn continue_job(job: &mut Job) {
+    job.state = JobState::Running;
+    unsafe { libc::kill(-job.pgid, libc::SIGCONT) };
+}
+
+/// The `fg` builtin
+pub fn foreground(spec: Option<&str>) -> Result<i32> {
+    let mut job = {
+        let mut jobs = JOBS.lock().unwrap();
+        let index = find(&jobs, "fg", spec)?;
+        jobs.remove(index)
+    };
+
+    println!("{}", job.command);
+    if ENABLED.load(Ordering::Relaxed) {
+        give_terminal(job.pgid);
+        set_terminal_modes(&job.tmodes);
+    }
+    continue_job(&mut job);
+    Ok(shell::pipeline_status(&run_in_foreground(job)))
+}
+
+/// The `bg` builtin
+pub fn background(spec: Option<&str>) -> Result<()> {
+    let mut jobs = JOBS.lock().unwrap();
+    let index = find(&jobs, "bg", spec)?;
+    let mark = marker(index, jobs.len());
+
+    let job = &mut jobs[index];
+    continue_job(job);
+    println!("[{}]{} {} &", job.id, mark, job.command);
+    Ok(())
+}
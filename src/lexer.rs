@@ -0,0 +1,557 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    iter::Peekable,
+    str::Chars,
+};
+
+/// A piece of a word, tagged with how it was quoted
+#[derive(Debug, Clone, PartialEq)]
+pub enum WordPart {
+    Bare(String),         // unquoted text
+    SingleQuoted(String), // '...' or a backslash-escaped character
+    DoubleQuoted(String), // "..." with its escapes still in place
+}
+
+/// A shell word made of one or more adjacent parts
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    fn push_bare(&mut self, text: &str) {
+        if let Some(WordPart::Bare(s)) = self.parts.last_mut() {
+            s.push_str(text);
+        } else {
+            self.parts.push(WordPart::Bare(text.to_string()));
+        }
+    }
+
+    /// Text of the word with all quoting removed
+    pub fn text(&self) -> String {
+        self.parts.iter()
+            .map(|part| match part {
+                WordPart::Bare(s) | WordPart::SingleQuoted(s) => s.clone(),
+                WordPart::DoubleQuoted(s) => unescape_double(s),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(Word),
+    AndAnd,        // &&
+    OrOr,          // ||
+    Amp,           // &
+    Semi,          // ;
+    DSemi,         // ;;
+    SemiAnd,       // ;&
+    DSemiAnd,      // ;;&
+    LParen,        // (
+    RParen,        // )
+    Pipe,          // |
+    Newline,
+    Less,          // <
+    Great,         // >
+    DGreat,        // >>
+    LessAnd,       // <&
+    DLess,         // <<
+    DLessDash,     // <<-
+    TLess,         // <<<
+    GreatAnd,      // >&
+    AndGreat,      // &>
+    AndDGreat,     // &>>
+    IoNumber(i32), // the fd in front of a redirection, as in 2>
+}
+
+impl Token {
+    /// How the token looks in the source, for error messages
+    pub fn describe(&self) -> String {
+        match self {
+            Token::Word(w) => w.text(),
+            Token::AndAnd => "&&".to_string(),
+            Token::OrOr => "||".to_string(),
+            Token::Amp => "&".to_string(),
+            Token::Semi => ";".to_string(),
+            Token::DSemi => ";;".to_string(),
+            Token::SemiAnd => ";&".to_string(),
+            Token::DSemiAnd => ";;&".to_string(),
+            Token::LParen => "(".to_string(),
+            Token::RParen => ")".to_string(),
+            Token::Pipe => "|".to_string(),
+            Token::Newline => "newline".to_string(),
+            Token::Less => "<".to_string(),
+            Token::Great => ">".to_string(),
+            Token::DGreat => ">>".to_string(),
+            Token::LessAnd => "<&".to_string(),
+            Token::DLess => "<<".to_string(),
+            Token::DLessDash => "<<-".to_string(),
+            Token::TLess => "<<<".to_string(),
+            Token::GreatAnd => ">&".to_string(),
+            Token::AndGreat => "&>".to_string(),
+            Token::AndDGreat => "&>>".to_string(),
+            Token::IoNumber(n) => n.to_string(),
+        }
+    }
+}
+
+/// Remove the backslashes that are special inside double quotes
+pub fn unescape_double(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.peek() {
+                Some('$' | '`' | '"' | '\\') => {
+                    result.push(chars.next().unwrap());
+                    continue;
+                }
+                Some('\n') => {
+                    chars.next();
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Read up to the bracket closing an already opened `open`, skipping over quotes
+fn read_nested(chars: &mut Peekable<Chars>, open: char, close: char, what: &str) -> Result<String> {
+    let mut body = String::new();
+    let mut depth = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == close && depth == 0 => return Ok(body),
+            c if c == close => depth -= 1,
+            c if c == open => depth += 1,
+            '\\' => {
+                body.push(c);
+                match chars.next() {
+                    Some(n) => body.push(n),
+                    None => break,
+                }
+                continue;
+            }
+            '\'' | '"' | '`' => {
+                body.push(c);
+                loop {
+                    match chars.next() {
+                        Some(n) if n == c => break,
+                        Some('\\') if c != '\'' => {
+                            body.push('\\');
+                            if let Some(n) = chars.next() {
+                                body.push(n);
+                            }
+                            continue;
+                        }
+                        Some(n) => body.push(n),
+                        None => return Err(incomplete(&format!("unterminated quote in {}", what))),
+                    }
+                }
+            }
+            _ => {}
+        }
+        body.push(c);
+    }
+    Err(incomplete(&format!("unterminated {}", what)))
+}
+
+/// Read the body of `$(...)` after the opening parenthesis, consuming the matching `)`
+pub fn read_paren_body(chars: &mut Peekable<Chars>) -> Result<String> {
+    read_nested(chars, '(', ')', "command substitution")
+}
+
+/// Read the body of `${...}` after the opening brace, consuming the matching `}`
+pub fn read_brace_body(chars: &mut Peekable<Chars>) -> Result<String> {
+    read_nested(chars, '{', '}', "parameter expansion")
+}
+
+/// Read the body of `` `...` `` after the opening backquote, keeping its escapes
+pub fn read_backquoted(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut body = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => return Ok(body),
+            '\\' => {
+                body.push(c);
+                if let Some(n) = chars.next() {
+                    body.push(n);
+                }
+            }
+            _ => body.push(c),
+        }
+    }
+    Err(incomplete("unterminated backquote"))
+}
+
+/// Remove the backslashes that are special inside backquotes
+pub fn unescape_backquoted(body: &str) -> String {
+    let mut result = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' && matches!(chars.peek(), Some('$' | '`' | '\\')) {
+            result.push(chars.next().unwrap());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Error for input that is valid so far but needs more lines
+pub fn incomplete(msg: &str) -> Error {
+    Error::new(ErrorKind::UnexpectedEof, format!("shesh: {}", msg))
+}
+
+/// Read the quoted or unquoted piece of a word that starts with `c`
+fn read_word_char(c: char, chars: &mut Peekable<Chars>, word: &mut Word) -> Result<()> {
+    match c {
+        '\\' => match chars.next() {
+            Some('\n') => {}
+            Some(n) => word.parts.push(WordPart::SingleQuoted(n.to_string())),
+            None => return Err(incomplete("unexpected end of input after `\\`")),
+        },
+        '\'' => {
+            let mut quoted = String::new();
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(n) => quoted.push(n),
+                    None => return Err(incomplete("unterminated single quote")),
+                }
+            }
+            word.parts.push(WordPart::SingleQuoted(quoted));
+        }
+        '"' => {
+            let mut quoted = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        quoted.push('\\');
+                        if let Some(n) = chars.next() {
+                            quoted.push(n);
+                        }
+                    }
+                    Some('$') if chars.next_if_eq(&'(').is_some() => {
+                        quoted.push_str(&format!("$({})", read_paren_body(chars)?));
+                    }
+                    Some('$') if chars.next_if_eq(&'{').is_some() => {
+                        quoted.push_str(&format!("${{{}}}", read_brace_body(chars)?));
+                    }
+                    Some('`') => {
+                        quoted.push_str(&format!("`{}`", read_backquoted(chars)?));
+                    }
+                    Some(n) => quoted.push(n),
+                    None => return Err(incomplete("unterminated double quote")),
+                }
+            }
+            word.parts.push(WordPart::DoubleQuoted(quoted));
+        }
+        '$' if chars.next_if_eq(&'(').is_some() => {
+            let body = read_paren_body(chars)?;
+            word.push_bare(&format!("$({})", body));
+        }
+        '$' if chars.next_if_eq(&'{').is_some() => {
+            let body = read_brace_body(chars)?;
+            word.push_bare(&format!("${{{}}}", body));
+        }
+        '`' => {
+            let body = read_backquoted(chars)?;
+            word.push_bare(&format!("`{}`", body));
+        }
+        _ => word.push_bare(c.encode_utf8(&mut [0; 4])),
+    }
+    Ok(())
+}
+
+/// Read `text` as one word, honouring quotes but not splitting on blanks or operators
+pub fn parse_word(text: &str) -> Result<Word> {
+    let mut word = Word::default();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        read_word_char(c, &mut chars, &mut word)?;
+    }
+    Ok(word)
+}
+
+fn flush_word(tokens: &mut Vec<Token>, word: &mut Option<Word>) {
+    if let Some(w) = word.take() {
+        tokens.push(Token::Word(w));
+    }
+}
+
+/// Read the lines of a here-document up to its delimiter line
+fn read_heredoc_body(chars: &mut Peekable<Chars>, delimiter: &str, strip_tabs: bool) -> Result<String> {
+    let mut body = String::new();
+
+    while chars.peek().is_some() {
+        let mut line = String::new();
+        let mut ended = false;
+        for c in chars.by_ref() {
+            if c == '\n' {
+                ended = true;
+                break;
+            }
+            line.push(c);
+        }
+
+        let line = if strip_tabs { line.trim_start_matches('\t') } else { &line };
+        if line == delimiter {
+            return Ok(body);
+        }
+        if !ended {
+            break;
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+    Err(incomplete(&format!("here-document delimited by `{}' is not terminated", delimiter)))
+}
+
+/// Replace the delimiter word of each here-document on a line with its body.
+/// The body is double quoted, so that `$` expansions still happen, unless
+/// some part of the delimiter was quoted.
+fn read_heredocs(chars: &mut Peekable<Chars>, line: &mut [Token]) -> Result<()> {
+    for i in 1..line.len() {
+        let strip_tabs = match line[i - 1] {
+            Token::DLess => false,
+            Token::DLessDash => true,
+            _ => continue,
+        };
+        let Token::Word(delimiter) = &line[i] else {
+            continue;
+        };
+
+        let quoted = delimiter.parts.iter().any(|part| !matches!(part, WordPart::Bare(_)));
+        let body = read_heredoc_body(chars, &delimiter.text(), strip_tabs)?;
+        let part = if quoted {
+            WordPart::SingleQuoted(body)
+        } else {
+            WordPart::DoubleQuoted(escape_double_quotes(&body))
+        };
+        line[i] = Token::Word(Word { parts: vec![part] });
+    }
+    Ok(())
+}
+
+/// Protect `\"` so it stays as written when the text is treated as double
+/// quoted; in a here-document a backslash only escapes `$`, `` ` `` and `\`
+fn escape_double_quotes(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Double quoting turns `\\"` back into `\"`
+            Some('"') => result.push_str("\\\\\""),
+            Some(n) => {
+                result.push('\\');
+                result.push(n);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// End the word before a redirection operator; an unquoted number directly
+/// in front of it, as in `2>`, names the fd being redirected
+fn flush_redirect_word(tokens: &mut Vec<Token>, word: &mut Option<Word>) {
+    if let Some(Word { parts }) = word
+        && let [WordPart::Bare(text)] = parts.as_slice()
+        && text.chars().all(|c| c.is_ascii_digit())
+        && let Ok(fd) = text.parse()
+    {
+        tokens.push(Token::IoNumber(fd));
+        *word = None;
+        return;
+    }
+    flush_word(tokens, word);
+}
+
+/// Split a line of input into words and operators. Also returns the indices
+/// of the tokens that have blanks in front of them, in ascending order.
+pub fn tokenize(input: &str) -> Result<(Vec<Token>, Vec<usize>)> {
+    let mut tokens = Vec::new();
+    let mut blanks = Vec::new();
+    let mut word: Option<Word> = None;
+    let mut chars = input.chars().peekable();
+    // Here-document bodies start on the line after the operators that introduce them
+    let mut line_start = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\r' => {
+                flush_word(&mut tokens, &mut word);
+                blanks.push(tokens.len());
+            }
+            '\n' => {
+                flush_word(&mut tokens, &mut word);
+                read_heredocs(&mut chars, &mut tokens[line_start..])?;
+                tokens.push(Token::Newline);
+                line_start = tokens.len();
+            }
+            '#' if word.is_none() => {
+                // Comment runs until the end of the line
+                while chars.peek().is_some_and(|&n| n != '\n') {
+                    chars.next();
+                }
+            }
+            ';' => {
+                flush_word(&mut tokens, &mut word);
+                if chars.next_if_eq(&';').is_some() {
+                    if chars.next_if_eq(&'&').is_some() {
+                        tokens.push(Token::DSemiAnd);
+                    } else {
+                        tokens.push(Token::DSemi);
+                    }
+                } else if chars.next_if_eq(&'&').is_some() {
+                    tokens.push(Token::SemiAnd);
+                } else {
+                    tokens.push(Token::Semi);
+                }
+            }
+            '(' => {
+                flush_word(&mut tokens, &mut word);
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                flush_word(&mut tokens, &mut word);
+                tokens.push(Token::RParen);
+            }
+            '&' => {
+                flush_word(&mut tokens, &mut word);
+                if chars.next_if_eq(&'&').is_some() {
+                    tokens.push(Token::AndAnd);
+                } else if chars.next_if_eq(&'>').is_some() {
+                    if chars.next_if_eq(&'>').is_some() {
+                        tokens.push(Token::AndDGreat);
+                    } else {
+                        tokens.push(Token::AndGreat);
+                    }
+                } else {
+                    tokens.push(Token::Amp);
+                }
+            }
+            '|' => {
+                flush_word(&mut tokens, &mut word);
+                if chars.next_if_eq(&'|').is_some() {
+                    tokens.push(Token::OrOr);
+                } else {
+                    tokens.push(Token::Pipe);
+                }
+            }
+            '<' => {
+                flush_redirect_word(&mut tokens, &mut word);
+                if chars.next_if_eq(&'&').is_some() {
+                    tokens.push(Token::LessAnd);
+                } else if chars.next_if_eq(&'<').is_some() {
+                    if chars.next_if_eq(&'<').is_some() {
+                        tokens.push(Token::TLess);
+                    } else if chars.next_if_eq(&'-').is_some() {
+                        tokens.push(Token::DLessDash);
+                    } else {
+                        tokens.push(Token::DLess);
+                    }
+                } else {
+                    tokens.push(Token::Less);
+                }
+            }
+            '>' => {
+                flush_redirect_word(&mut tokens, &mut word);
+                if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(Token::DGreat);
+                } else if chars.next_if_eq(&'&').is_some() {
+                    tokens.push(Token::GreatAnd);
+                } else {
+                    tokens.push(Token::Great);
+                }
+            }
+            '\\' if chars.next_if_eq(&'\n').is_some() => {}
+            _ => read_word_char(c, &mut chars, word.get_or_insert_default())?,
+        }
+    }
+
+    flush_word(&mut tokens, &mut word);
+    read_heredocs(&mut chars, &mut tokens[line_start..])?;
+    Ok((tokens, blanks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input).unwrap().0
+    }
+
+    fn bare(text: &str) -> Token {
+        Token::Word(Word { parts: vec![WordPart::Bare(text.to_string())] })
+    }
+
+    #[test]
+    fn number_before_a_redirection_is_an_io_number() {
+        assert_eq!(
+            tokens("cmd 2>&1 >out"),
+            [bare("cmd"), Token::IoNumber(2), Token::GreatAnd, bare("1"), Token::Great, bare("out")]
+        );
+        // Only a bare number directly in front of the operator names an fd
+        assert_eq!(tokens("echo 2 >x"), [bare("echo"), bare("2"), Token::Great, bare("x")]);
+        assert_eq!(tokens("echo a2>x"), [bare("echo"), bare("a2"), Token::Great, bare("x")]);
+    }
+
+    #[test]
+    fn ampersand_redirections() {
+        assert_eq!(tokens("cmd &>>log"), [bare("cmd"), Token::AndDGreat, bare("log")]);
+        assert_eq!(tokens("cmd &>log"), [bare("cmd"), Token::AndGreat, bare("log")]);
+        assert_eq!(tokens("a && b &"), [bare("a"), Token::AndAnd, bare("b"), Token::Amp]);
+    }
+
+    #[test]
+    fn heredoc_body_replaces_the_delimiter() {
+        let body = |part| Token::Word(Word { parts: vec![part] });
+        assert_eq!(
+            tokens("cat <<EOF\nhi $x\nEOF\n"),
+            [
+                bare("cat"),
+                Token::DLess,
+                body(WordPart::DoubleQuoted("hi $x\n".to_string())),
+                Token::Newline,
+            ]
+        );
+        // A quoted delimiter leaves the body unexpanded, and <<- strips leading tabs
+        assert_eq!(
+            tokens("cat <<-'EOF'\n\thi $x\n\tEOF\n"),
+            [
+                bare("cat"),
+                Token::DLessDash,
+                body(WordPart::SingleQuoted("hi $x\n".to_string())),
+                Token::Newline,
+            ]
+        );
+        let unterminated = tokenize("cat <<EOF\nhi\n").unwrap_err();
+        assert_eq!(unterminated.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn comments_run_to_the_end_of_the_line() {
+        assert_eq!(
+            tokens("echo a # b c\necho d#e"),
+            [bare("echo"), bare("a"), Token::Newline, bare("echo"), bare("d#e")]
+        );
+    }
+}
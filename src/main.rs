@@ -1,50 +1,68 @@
 mod builtins;
 mod commands;
 mod config;
+mod functions;
+mod glob;
 mod input;
+mod jobs;
+mod lexer;
+mod options;
+mod parser;
+mod script;
 mod shell;
 mod utils;
+mod vars;
 
-use std::io::Result;
-use commands::CommandSeparator;
+use std::{
+    env,
+    io::{self, ErrorKind, IsTerminal, Result},
+    process,
+};
+
+fn main() -> Result<()> {
+    vars::init();
+
+    // A script, a command string or piped input runs without the line editor
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("-c") => process::exit(script::run_command_string(&args[2..])),
+        Some(path) => process::exit(script::run_file(path, &args[2..])),
+        None if !io::stdin().is_terminal() => process::exit(script::run_stdin()),
+        None => {}
+    }
 
-fn main() -> Result<()> { 
     let config = config::init();
+    jobs::init();
     config::run_startup(&config);
 
     loop {
+        jobs::notify();
         let input = input::read_line_raw(&config::load_history());
-        let input = input.trim().split('#').next().unwrap().trim();
+        let input = input.trim();
         if input.is_empty() { continue; }
 
         // Expand aliases
-        let expanded_line = builtins::expand_aliases(input);
+        let mut expanded_line = builtins::expand_aliases(input);
         config::save_history(&expanded_line);
 
-        // Split commands
-        let tokens = commands::split_commands(&expanded_line);
-        let mut last_success = true;
-        let mut background_next = false;
-
-        for (cmd_str, separator) in tokens {
-            let background = background_next;
-            background_next = false;
-
-            match separator {
-                CommandSeparator::AndAnd if !last_success => {
-                    // Skip this command because previous failed
-                    continue;
-                },
-                CommandSeparator::Background => {
-                    background_next = true;
-                    // Continue to next command without executing this one as foreground
-                    continue;
-                },
-                _ => {}
+        // Parse and run the command list, reading more lines while it is incomplete
+        loop {
+            match parser::parse(&expanded_line) {
+                Ok(list) => {
+                    commands::run_list(&list);
+                    // An interrupt has unwound everything it needed to by now
+                    shell::take_flow();
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    if let Some(more) = input::read_continuation() {
+                        expanded_line.push('\n');
+                        expanded_line.push_str(&more);
+                        continue;
+                    }
+                }
+                Err(e) => eprintln!("{}", e),
             }
-
-            // Execute the command
-            last_success = commands::process_command(&cmd_str, background);
+            break;
         }
     }
 }
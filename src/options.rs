@@ -0,0 +1,53 @@
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+/// Options that can be toggled with `set -o name` / `set +o name`
+const KNOWN: &[&str] = &[
+    "silentbg", // discard stdout and stderr of background jobs
+    "failglob", // a pattern that matches nothing is an error
+    "pipefail", // a pipeline fails if any of its commands fails
+];
+
+static ENABLED: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+pub fn is_set(name: &str) -> bool {
+    ENABLED.lock().unwrap().contains(&name)
+}
+
+fn set_option(name: &str, on: bool) -> Result<()> {
+    let Some(known) = KNOWN.iter().find(|k| **k == name) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("shesh: set: {}: invalid option name", name)
+        ));
+    };
+
+    let mut enabled = ENABLED.lock().unwrap();
+    enabled.retain(|n| n != known);
+    if on {
+        enabled.push(known);
+    }
+    Ok(())
+}
+
+/// The `set` builtin
+pub fn handle_set(args: &[&str]) -> Result<()> {
+    match args {
+        [] | ["-o"] | ["+o"] => {
+            for name in KNOWN {
+                println!("{:<15}{}", name, if is_set(name) { "on" } else { "off" });
+            }
+            Ok(())
+        }
+        [flag @ ("-o" | "+o"), names @ ..] => {
+            for name in names {
+                set_option(name, *flag == "-o")?;
+            }
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: set [-o|+o] [option...]"
+        )),
+    }
+}
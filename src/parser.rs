@@ -0,0 +1,840 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+};
+
+use crate::{
+    builtins::condition,
+    lexer::{self, Token, Word, WordPart},
+    vars,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandSeparator {
+    AndAnd,     // &&
+    OrOr,       // ||
+    SemiColon,  // ; or newline
+    Background, // &
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectOp {
+    Output,     // >
+    Append,     // >>
+    Input,      // <
+    DupOutput,  // >&
+    DupInput,   // <&
+    OutputBoth, // &>
+    AppendBoth, // &>>
+    HereDoc,    // << or <<-, with the body as the target
+    HereString, // <<<
+}
+
+impl RedirectOp {
+    /// The fd an operator applies to when no number is written in front of it
+    fn default_fd(self) -> i32 {
+        match self {
+            RedirectOp::Input
+            | RedirectOp::DupInput
+            | RedirectOp::HereDoc
+            | RedirectOp::HereString => 0,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RedirectNode {
+    pub fd: i32,
+    pub op: RedirectOp,
+    pub target: Word,
+}
+
+/// Assignments, words and redirections of a single command, before expansion
+#[derive(Debug, Clone, Default)]
+pub struct SimpleCommand {
+    pub assignments: Vec<(String, Word)>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<RedirectNode>,
+}
+
+/// How a `case` item ends: what happens after its commands run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseEnd {
+    Break,       // ;;
+    FallThrough, // ;&, run the next item's commands too
+    Continue,    // ;;&, go on testing the next patterns
+}
+
+/// `pattern | pattern) commands` in a `case` command
+#[derive(Debug, Clone)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub end: CaseEnd,
+}
+
+/// An expression inside `[[ ]]`, with its words still to be expanded
+#[derive(Debug, Clone)]
+pub enum CondExpr {
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+    Unary(String, Word),
+    Binary(Word, String, Word),
+    Word(Word),
+}
+
+/// Commands made of other lists, like `if`
+#[derive(Debug, Clone)]
+pub enum CompoundCommand {
+    /// The `if` and `elif` branches as (condition, body) pairs, then `else`
+    If {
+        branches: Vec<(List, List)>,
+        otherwise: Option<List>,
+    },
+    /// `for name in words`, or over the positional parameters without `in`
+    For {
+        name: String,
+        words: Option<Vec<Word>>,
+        body: List,
+    },
+    /// `while`, or `until` when the condition is negated
+    While {
+        condition: List,
+        body: List,
+        until: bool,
+    },
+    Case {
+        word: Word,
+        items: Vec<CaseItem>,
+    },
+    /// `{ list; }`, run in the current shell
+    Group(List),
+    /// `[[ expression ]]`
+    Conditional(CondExpr),
+}
+
+impl CompoundCommand {
+    /// Short text for the command in job listings
+    pub fn describe(&self) -> &'static str {
+        match self {
+            CompoundCommand::If { .. } => "if ... fi",
+            CompoundCommand::For { .. } => "for ... done",
+            CompoundCommand::While { until: false, .. } => "while ... done",
+            CompoundCommand::While { until: true, .. } => "until ... done",
+            CompoundCommand::Case { .. } => "case ... esac",
+            CompoundCommand::Group(_) => "{ ... }",
+            CompoundCommand::Conditional(_) => "[[ ... ]]",
+        }
+    }
+}
+
+/// What a function runs when it is called
+#[derive(Debug)]
+pub struct FunctionBody {
+    pub command: CompoundCommand,
+    pub redirects: Vec<RedirectNode>,
+}
+
+/// One stage of a pipeline
+#[derive(Debug, Clone)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// A compound command with the redirections written after it
+    Compound(CompoundCommand, Vec<RedirectNode>),
+    /// `name() compound-command` or `function name compound-command`
+    Function(String, Arc<FunctionBody>),
+}
+
+/// Commands joined by `|`
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+/// Pipelines joined by `&&` and `||`, evaluated left to right
+#[derive(Debug, Clone)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(CommandSeparator, Pipeline)>,
+}
+
+impl AndOr {
+    /// Short text for the list in job listings, such as `... && ...`
+    pub fn describe(&self) -> String {
+        let mut text = "...".to_string();
+        for (separator, _) in &self.rest {
+            text += if *separator == CommandSeparator::OrOr { " || ..." } else { " && ..." };
+        }
+        text
+    }
+}
+
+/// And-or lists, each with the separator that ended it
+#[derive(Debug, Clone, Default)]
+pub struct List {
+    pub items: Vec<(AndOr, CommandSeparator)>,
+}
+
+/// Parse a complete piece of shell input into a list
+pub fn parse(input: &str) -> Result<List> {
+    let (tokens, blanks) = lexer::tokenize(input)?;
+    let mut parser = Parser { tokens, blanks, pos: 0 };
+    let list = parser.parse_list()?;
+
+    match parser.peek() {
+        Some(tok) => Err(unexpected(tok)),
+        None => Ok(list),
+    }
+}
+
+/// Reserved words that end a list inside a compound command
+const CLOSING_WORDS: &[&str] = &["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+/// The text of a word that could be a reserved word: one unquoted part
+fn keyword(token: Option<&Token>) -> Option<&str> {
+    match token {
+        Some(Token::Word(Word { parts })) => match parts.as_slice() {
+            [WordPart::Bare(text)] => Some(text),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Names a function can have: more characters are allowed than in variable names
+fn is_function_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | ':'))
+}
+
+/// The operator a token is in the middle of a `[[ ]]` comparison
+fn cond_binary_op(token: Option<&Token>) -> Option<String> {
+    match token {
+        Some(Token::Less) => Some("<".to_string()),
+        Some(Token::Great) => Some(">".to_string()),
+        tok => keyword(tok)
+            .filter(|op| condition::is_binary_op(op) || *op == "=~")
+            .map(str::to_string),
+    }
+}
+
+fn redirect_op(token: &Token) -> Option<RedirectOp> {
+    match token {
+        Token::Great => Some(RedirectOp::Output),
+        Token::DGreat => Some(RedirectOp::Append),
+        Token::Less => Some(RedirectOp::Input),
+        Token::GreatAnd => Some(RedirectOp::DupOutput),
+        Token::LessAnd => Some(RedirectOp::DupInput),
+        Token::AndGreat => Some(RedirectOp::OutputBoth),
+        Token::AndDGreat => Some(RedirectOp::AppendBoth),
+        Token::DLess | Token::DLessDash => Some(RedirectOp::HereDoc),
+        Token::TLess => Some(RedirectOp::HereString),
+        _ => None,
+    }
+}
+
+/// Split a `NAME=value` word into the name and the value word
+fn split_assignment(word: &Word) -> Option<(String, Word)> {
+    let Some(WordPart::Bare(first)) = word.parts.first() else {
+        return None;
+    };
+    let (name, value) = first.split_once('=')?;
+    if !vars::is_valid_name(name) {
+        return None;
+    }
+
+    let mut parts = Vec::new();
+    if !value.is_empty() {
+        parts.push(WordPart::Bare(value.to_string()));
+    }
+    parts.extend(word.parts[1..].iter().cloned());
+    Some((name.to_string(), Word { parts }))
+}
+
+fn unexpected(tok: &Token) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("shesh: syntax error near unexpected token `{}'", tok.describe())
+    )
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    /// Indices of the tokens that have blanks in front of them
+    blanks: Vec<usize>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    /// Error for the token at the current position, or ask for more input
+    /// when there is none
+    fn unexpected_here(&self) -> Error {
+        match self.peek() {
+            Some(tok) => unexpected(tok),
+            None => lexer::incomplete("unexpected end of input"),
+        }
+    }
+
+    /// Consume the reserved word `word` or fail
+    fn expect(&mut self, word: &str) -> Result<()> {
+        if keyword(self.peek()) != Some(word) {
+            return Err(self.unexpected_here());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Consume the operator `tok` or fail
+    fn expect_token(&mut self, tok: &Token) -> Result<()> {
+        if self.peek() != Some(tok) {
+            return Err(self.unexpected_here());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn starts_command(&self) -> bool {
+        if keyword(self.peek()).is_some_and(|word| CLOSING_WORDS.contains(&word)) {
+            return false;
+        }
+        matches!(self.peek(), Some(Token::Word(_) | Token::IoNumber(_)))
+            || self.peek().and_then(redirect_op).is_some()
+    }
+
+    fn parse_list(&mut self) -> Result<List> {
+        let mut list = List::default();
+        self.skip_newlines();
+
+        while self.starts_command() {
+            let and_or = self.parse_and_or()?;
+            let separator = match self.peek() {
+                Some(Token::Semi | Token::Newline) => CommandSeparator::SemiColon,
+                Some(Token::Amp) => CommandSeparator::Background,
+                _ => CommandSeparator::None,
+            };
+            list.items.push((and_or, separator));
+
+            if separator == CommandSeparator::None {
+                break;
+            }
+            self.pos += 1;
+            self.skip_newlines();
+        }
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+
+        loop {
+            let separator = match self.peek() {
+                Some(Token::AndAnd) => CommandSeparator::AndAnd,
+                Some(Token::OrOr) => CommandSeparator::OrOr,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((separator, self.parse_pipeline()?));
+        }
+        Ok(AndOr { first, rest })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline> {
+        let mut commands = vec![self.parse_command()?];
+
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.parse_command()?);
+        }
+        Ok(Pipeline { commands })
+    }
+
+    /// A list inside a compound command, which may not be empty
+    fn parse_compound_list(&mut self) -> Result<List> {
+        let list = self.parse_list()?;
+        if list.items.is_empty() {
+            return Err(self.unexpected_here());
+        }
+        Ok(list)
+    }
+
+    /// The rest of an `if` command, up to and including its `fi`
+    fn parse_if(&mut self) -> Result<CompoundCommand> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+
+        loop {
+            let condition = self.parse_compound_list()?;
+            self.expect("then")?;
+            branches.push((condition, self.parse_compound_list()?));
+
+            match keyword(self.peek()) {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    otherwise = Some(self.parse_compound_list()?);
+                    self.expect("fi")?;
+                    break;
+                }
+                _ => {
+                    self.expect("fi")?;
+                    break;
+                }
+            }
+        }
+        Ok(CompoundCommand::If { branches, otherwise })
+    }
+
+    /// `do list done`, the body of a loop
+    fn parse_do_group(&mut self) -> Result<List> {
+        self.expect("do")?;
+        let body = self.parse_compound_list()?;
+        self.expect("done")?;
+        Ok(body)
+    }
+
+    /// The rest of a `for` loop: `name [in words]; do list done`
+    fn parse_for(&mut self) -> Result<CompoundCommand> {
+        let name = match keyword(self.peek()) {
+            Some(name) if vars::is_valid_name(name) => name.to_string(),
+            _ => return Err(self.unexpected_here()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+
+        let mut words = None;
+        if keyword(self.peek()) == Some("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(w)) = self.peek() {
+                list.push(w.clone());
+                self.pos += 1;
+            }
+            match self.peek() {
+                Some(Token::Semi | Token::Newline) => self.pos += 1,
+                _ => return Err(self.unexpected_here()),
+            }
+            words = Some(list);
+        } else if self.peek() == Some(&Token::Semi) {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+
+        Ok(CompoundCommand::For { name, words, body: self.parse_do_group()? })
+    }
+
+    /// The rest of a `case` command: `word in items esac`
+    fn parse_case(&mut self) -> Result<CompoundCommand> {
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(tok) => return Err(unexpected(&tok)),
+            None => return Err(lexer::incomplete("unexpected end of input")),
+        };
+        self.skip_newlines();
+        self.expect("in")?;
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        while keyword(self.peek()) != Some("esac") {
+            if self.peek() == Some(&Token::LParen) {
+                self.pos += 1;
+            }
+
+            let mut patterns = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern),
+                    Some(tok) => return Err(unexpected(&tok)),
+                    None => return Err(lexer::incomplete("unexpected end of input")),
+                }
+                match self.next() {
+                    Some(Token::Pipe) => {}
+                    Some(Token::RParen) => break,
+                    Some(tok) => return Err(unexpected(&tok)),
+                    None => return Err(lexer::incomplete("unexpected end of input")),
+                }
+            }
+
+            // The commands may be empty, and the last item needs no terminator
+            let body = self.parse_list()?;
+            let end = match self.peek() {
+                Some(Token::DSemi) => CaseEnd::Break,
+                Some(Token::SemiAnd) => CaseEnd::FallThrough,
+                Some(Token::DSemiAnd) => CaseEnd::Continue,
+                _ if keyword(self.peek()) == Some("esac") => {
+                    items.push(CaseItem { patterns, body, end: CaseEnd::Break });
+                    break;
+                }
+                _ => return Err(self.unexpected_here()),
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            items.push(CaseItem { patterns, body, end });
+        }
+        self.expect("esac")?;
+
+        Ok(CompoundCommand::Case { word, items })
+    }
+
+    /// An operand inside `[[ ]]`; fd numbers the lexer split off, as in
+    /// `[[ 2 > 1 ]]`, are plain words here
+    fn cond_word(&mut self) -> Result<Word> {
+        self.skip_newlines();
+        let word = match self.peek() {
+            Some(Token::Word(word)) if keyword(self.peek()) != Some("]]") => word.clone(),
+            Some(Token::IoNumber(n)) => Word { parts: vec![WordPart::Bare(n.to_string())] },
+            _ => return Err(self.unexpected_here()),
+        };
+        self.pos += 1;
+        Ok(word)
+    }
+
+    /// The regular expression after `=~`. Its parentheses and `|` are part
+    /// of it rather than operators, so tokens are joined until one has blanks
+    /// in front of it.
+    fn cond_regex(&mut self) -> Result<Word> {
+        let mut regex = Word::default();
+        loop {
+            if !regex.parts.is_empty() && self.blanks.binary_search(&self.pos).is_ok() {
+                break;
+            }
+            match self.peek() {
+                Some(Token::Word(word)) if keyword(self.peek()) != Some("]]") => {
+                    regex.parts.extend(word.parts.iter().cloned());
+                }
+                Some(tok @ (Token::LParen | Token::RParen | Token::Pipe)) => {
+                    regex.parts.push(WordPart::Bare(tok.describe()));
+                }
+                _ => break,
+            }
+            self.pos += 1;
+        }
+
+        if regex.parts.is_empty() {
+            return Err(self.unexpected_here());
+        }
+        Ok(regex)
+    }
+
+    fn cond_primary(&mut self) -> Result<CondExpr> {
+        self.skip_newlines();
+        if keyword(self.peek()) == Some("!") {
+            self.pos += 1;
+            return Ok(CondExpr::Not(Box::new(self.cond_primary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let expr = self.cond_or()?;
+            self.skip_newlines();
+            self.expect_token(&Token::RParen)?;
+            return Ok(expr);
+        }
+
+        // Comparisons come first, so that `[[ -f = x ]]` compares strings
+        if let Some(op) = cond_binary_op(self.tokens.get(self.pos + 1)) {
+            let left = self.cond_word()?;
+            self.pos += 1;
+            let right = if op == "=~" { self.cond_regex()? } else { self.cond_word()? };
+            return Ok(CondExpr::Binary(left, op, right));
+        }
+
+        if let Some(op) = keyword(self.peek()).filter(|op| condition::is_unary_op(op))
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Word(_)))
+            && keyword(self.tokens.get(self.pos + 1)) != Some("]]")
+        {
+            let op = op.to_string();
+            self.pos += 1;
+            return Ok(CondExpr::Unary(op, self.cond_word()?));
+        }
+        Ok(CondExpr::Word(self.cond_word()?))
+    }
+
+    fn cond_and(&mut self) -> Result<CondExpr> {
+        let mut expr = self.cond_primary()?;
+        while self.peek() == Some(&Token::AndAnd) {
+            self.pos += 1;
+            expr = CondExpr::And(Box::new(expr), Box::new(self.cond_primary()?));
+        }
+        Ok(expr)
+    }
+
+    fn cond_or(&mut self) -> Result<CondExpr> {
+        let mut expr = self.cond_and()?;
+        while self.peek() == Some(&Token::OrOr) {
+            self.pos += 1;
+            expr = CondExpr::Or(Box::new(expr), Box::new(self.cond_and()?));
+        }
+        Ok(expr)
+    }
+
+    /// A redirection at the current position, if there is one
+    fn parse_redirect(&mut self) -> Result<Option<RedirectNode>> {
+        let start = self.pos;
+        let fd = match self.peek() {
+            Some(Token::IoNumber(n)) => {
+                let n = *n;
+                self.pos += 1;
+                Some(n)
+            }
+            _ => None,
+        };
+        // The lexer only makes an fd number when a redirection operator follows
+        let Some(op) = self.peek().and_then(redirect_op) else {
+            self.pos = start;
+            return Ok(None);
+        };
+        self.pos += 1;
+
+        match self.next() {
+            Some(Token::Word(target)) => Ok(Some(RedirectNode {
+                fd: fd.unwrap_or(op.default_fd()),
+                op,
+                target,
+            })),
+            Some(tok) => Err(unexpected(&tok)),
+            None => Err(unexpected(&Token::Newline)),
+        }
+    }
+
+    /// The name of a function whose definition starts here: `name()` or
+    /// `function name`, which may also have the parentheses
+    fn parse_function_name(&mut self) -> Result<Option<String>> {
+        let name = if keyword(self.peek()) == Some("function") {
+            self.pos += 1;
+            let name = match keyword(self.peek()) {
+                Some(name) if is_function_name(name) => name.to_string(),
+                _ => return Err(self.unexpected_here()),
+            };
+            self.pos += 1;
+            if self.peek() == Some(&Token::LParen) {
+                self.pos += 1;
+                self.expect_token(&Token::RParen)?;
+            }
+            name
+        } else if let Some(name) = keyword(self.peek())
+            && is_function_name(name)
+            && self.tokens.get(self.pos + 1) == Some(&Token::LParen)
+        {
+            let name = name.to_string();
+            self.pos += 2;
+            self.expect_token(&Token::RParen)?;
+            name
+        } else {
+            return Ok(None);
+        };
+
+        self.skip_newlines();
+        Ok(Some(name))
+    }
+
+    /// A compound command and the redirections after it, if one starts here
+    fn parse_compound(&mut self) -> Result<Option<(CompoundCommand, Vec<RedirectNode>)>> {
+        let compound = match keyword(self.peek()) {
+            Some("if") => {
+                self.pos += 1;
+                self.parse_if()?
+            }
+            Some("for") => {
+                self.pos += 1;
+                self.parse_for()?
+            }
+            Some("case") => {
+                self.pos += 1;
+                self.parse_case()?
+            }
+            Some(word @ ("while" | "until")) => {
+                let until = word == "until";
+                self.pos += 1;
+                let condition = self.parse_compound_list()?;
+                CompoundCommand::While { condition, body: self.parse_do_group()?, until }
+            }
+            Some("[[") => {
+                self.pos += 1;
+                let expr = self.cond_or()?;
+                self.skip_newlines();
+                self.expect("]]")?;
+                CompoundCommand::Conditional(expr)
+            }
+            Some("{") => {
+                self.pos += 1;
+                let list = self.parse_compound_list()?;
+                self.expect("}")?;
+                CompoundCommand::Group(list)
+            }
+            _ => return Ok(None),
+        };
+
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.parse_redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(Some((compound, redirects)))
+    }
+
+    fn parse_command(&mut self) -> Result<Command> {
+        // Reserved words only count where a command name could start
+        if keyword(self.peek()).is_some_and(|word| CLOSING_WORDS.contains(&word)) {
+            return Err(self.unexpected_here());
+        }
+
+        if let Some(name) = self.parse_function_name()? {
+            let Some((command, redirects)) = self.parse_compound()? else {
+                return Err(self.unexpected_here());
+            };
+            return Ok(Command::Function(name, Arc::new(FunctionBody { command, redirects })));
+        }
+
+        match self.parse_compound()? {
+            Some((compound, redirects)) => Ok(Command::Compound(compound, redirects)),
+            None => self.parse_simple_command().map(Command::Simple),
+        }
+    }
+
+    fn parse_simple_command(&mut self) -> Result<SimpleCommand> {
+        let mut cmd = SimpleCommand::default();
+
+        loop {
+            if let Some(Token::Word(w)) = self.peek() {
+                // Assignments are only recognised before the command name
+                match split_assignment(w) {
+                    Some(assignment) if cmd.words.is_empty() => cmd.assignments.push(assignment),
+                    _ => cmd.words.push(w.clone()),
+                }
+                self.pos += 1;
+                continue;
+            }
+
+            match self.parse_redirect()? {
+                Some(redirect) => cmd.redirects.push(redirect),
+                None => break,
+            }
+        }
+
+        if cmd.assignments.is_empty() && cmd.words.is_empty() && cmd.redirects.is_empty() {
+            return Err(self.unexpected_here());
+        }
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The one command that `input` consists of
+    fn single(input: &str) -> Command {
+        let list = parse(input).unwrap();
+        let [(and_or, _)] = list.items.as_slice() else {
+            panic!("expected one and-or list: {:?}", list);
+        };
+        assert!(and_or.rest.is_empty());
+        let [command] = and_or.first.commands.as_slice() else {
+            panic!("expected one command: {:?}", and_or);
+        };
+        command.clone()
+    }
+
+    /// Words of every simple command in a list
+    fn words(list: &List) -> Vec<Vec<String>> {
+        list.items.iter()
+            .flat_map(|(and_or, _)| &and_or.first.commands)
+            .map(|command| match command {
+                Command::Simple(cmd) => cmd.words.iter().map(Word::text).collect(),
+                other => panic!("expected a simple command: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn if_with_elif_and_else() {
+        let Command::Compound(CompoundCommand::If { branches, otherwise }, _) =
+            single("if a; then b; elif c\nthen d; else e; fi")
+        else {
+            panic!("expected if");
+        };
+        let branches: Vec<_> = branches.iter().map(|(c, b)| (words(c), words(b))).collect();
+        assert_eq!(branches, [
+            (vec![vec!["a".to_string()]], vec![vec!["b".to_string()]]),
+            (vec![vec!["c".to_string()]], vec![vec!["d".to_string()]]),
+        ]);
+        assert_eq!(words(&otherwise.unwrap()), [["e"]]);
+    }
+
+    #[test]
+    fn for_with_and_without_words() {
+        let Command::Compound(CompoundCommand::For { name, words: Some(items), body }, _) =
+            single("for i in a 'b c'; do echo $i; done")
+        else {
+            panic!("expected for with words");
+        };
+        assert_eq!(name, "i");
+        assert_eq!(items.iter().map(Word::text).collect::<Vec<_>>(), ["a", "b c"]);
+        assert_eq!(words(&body), [["echo", "$i"]]);
+
+        let Command::Compound(CompoundCommand::For { words: None, .. }, _) =
+            single("for arg do :; done")
+        else {
+            panic!("expected for over the positional parameters");
+        };
+    }
+
+    #[test]
+    fn case_item_terminators() {
+        let Command::Compound(CompoundCommand::Case { word, items }, _) =
+            single("case $x in a|b) one;& c) two;;& *) three;; esac")
+        else {
+            panic!("expected case");
+        };
+        assert_eq!(word.text(), "$x");
+        let patterns: Vec<Vec<String>> = items.iter()
+            .map(|item| item.patterns.iter().map(Word::text).collect())
+            .collect();
+        assert_eq!(patterns, [vec!["a", "b"], vec!["c"], vec!["*"]]);
+        let ends: Vec<CaseEnd> = items.iter().map(|item| item.end).collect();
+        assert_eq!(ends, [CaseEnd::FallThrough, CaseEnd::Continue, CaseEnd::Break]);
+        assert_eq!(words(&items[1].body), [["two"]]);
+    }
+
+    #[test]
+    fn function_definition() {
+        let Command::Function(name, body) = single("greet() { echo hi; } >out") else {
+            panic!("expected a function");
+        };
+        assert_eq!(name, "greet");
+        let CompoundCommand::Group(list) = &body.command else {
+            panic!("expected a group body");
+        };
+        assert_eq!(words(list), [["echo", "hi"]]);
+        assert_eq!(body.redirects.len(), 1);
+        assert_eq!(body.redirects[0].op, RedirectOp::Output);
+    }
+
+    #[test]
+    fn unfinished_compound_commands_are_incomplete() {
+        for input in ["if a; then b", "for i in a; do", "case x in", "f() {"] {
+            assert_eq!(parse(input).unwrap_err().kind(), ErrorKind::UnexpectedEof, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::{self, File},
+    io::{self, Error, ErrorKind, Read},
+    iter,
+    os::fd::AsFd,
+};
+
+use crate::{commands, parser, shell, vars};
+
+/// Exit status of a script that cannot be parsed
+const SYNTAX_ERROR: i32 = 2;
+
+/// Point a parse error at the line of the script where it was found
+fn at_line(name: &str, line: usize, e: &Error) -> String {
+    let message = e.to_string();
+    let message = message.strip_prefix("shesh: ").unwrap_or(&message);
+    format!("shesh: {}: line {}: {}", name, line, message)
+}
+
+/// Run shell source a line at a time. Each command runs as soon as it is
+/// complete, so it can rely on what earlier ones did. Returns the status of
+/// the last command, or 2 after a syntax error.
+pub fn run_lines(name: &str, lines: impl IntoIterator<Item = String>) -> i32 {
+    let mut pending = String::new();
+    let mut status = 0;
+    let mut line_number = 0;
+
+    for line in lines {
+        line_number += 1;
+        if !pending.is_empty() {
+            pending.push('\n');
+        }
+        pending.push_str(&line);
+
+        match parser::parse(&pending) {
+            // Blank and comment lines leave the status alone
+            Ok(list) if list.items.is_empty() => {}
+            Ok(list) => status = commands::run_list(&list),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue,
+            Err(e) => {
+                eprintln!("{}", at_line(name, line_number, &e));
+                return SYNTAX_ERROR;
+            }
+        }
+        pending.clear();
+
+        // `return` in a sourced file skips the rest of it
+        if shell::flow().is_some() {
+            return status;
+        }
+    }
+
+    if !pending.is_empty()
+        && let Err(e) = parser::parse(&pending) {
+        eprintln!("{}", at_line(name, line_number, &e));
+        return SYNTAX_ERROR;
+    }
+    status
+}
+
+/// Run a script file with `args` as its positional parameters
+pub fn run_file(path: &str, args: &[String]) -> i32 {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("shesh: {}: {}", path, e);
+            return 127;
+        }
+    };
+
+    vars::set_script_name(path);
+    vars::set_positional(args.to_vec());
+    run_lines(path, content.lines().map(str::to_string))
+}
+
+/// `shesh -c command [name [args...]]`
+pub fn run_command_string(args: &[String]) -> i32 {
+    let Some((command, rest)) = args.split_first() else {
+        eprintln!("shesh: -c: option requires an argument");
+        return SYNTAX_ERROR;
+    };
+
+    if let Some((name, params)) = rest.split_first() {
+        vars::set_script_name(name);
+        vars::set_positional(params.to_vec());
+    }
+    run_lines("-c", command.lines().map(str::to_string))
+}
+
+/// Lines of standard input, read a byte at a time so that input meant for
+/// the commands being run is not taken by the shell first
+fn stdin_lines() -> impl Iterator<Item = String> {
+    let mut stdin = io::stdin().as_fd().try_clone_to_owned().map(File::from).ok();
+
+    iter::from_fn(move || {
+        let stdin = stdin.as_mut()?;
+        let mut line = Vec::new();
+        let mut byte = [0];
+        loop {
+            match stdin.read(&mut byte) {
+                Ok(1) if byte[0] != b'\n' => line.push(byte[0]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                // End of input or an error ends the last line, if there is one
+                Ok(0) | Err(_) if line.is_empty() => return None,
+                _ => break,
+            }
+        }
+        Some(String::from_utf8_lossy(&line).into_owned())
+    })
+}
+
+/// Run commands piped into the shell when standard input is not a terminal
+pub fn run_stdin() -> i32 {
+    run_lines("stdin", stdin_lines())
+}
@@ -1,110 +1,525 @@
 use std::{
     fs::{File, OpenOptions},
-    io::Result,
+    io::{self, Error, ErrorKind, Result, Write},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
     process::{Command, Stdio},
+    sync::{
+        Mutex,
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+    },
     thread,
 };
-use crate::commands::Redirect;
+use crate::{commands::Redirect, jobs, options, vars};
 
-pub fn execute(cmd: &str, args: &[&str]) -> Result<()> {
-    let expanded_args: Vec<&str> = args.iter()
-        .flat_map(|arg| arg.split(','))
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .collect();
-    
-    Command::new(cmd)
-        .args(expanded_args)
-        .status()
-        .map_err(|e| {
-            // Custom error message for command not found
-            if e.kind() == std::io::ErrorKind::NotFound {
-                std::io::Error::new(
-                    std::io::ErrorKind::NotFound,
-                    format!("shesh: command not found: {}", cmd)
-                )
-            } else {
-                e
-            }
-        })
-        .map(|_| ())
+static LAST_STATUS: AtomicI32 = AtomicI32::new(0);
+
+/// Exit status of the most recent foreground pipeline (`$?`)
+pub fn last_status() -> i32 {
+    LAST_STATUS.load(Ordering::Relaxed)
 }
 
-pub fn execute_background(cmd: &str, args: &[&str]) -> Result<()> {
-    let cmd = cmd.to_string();
-    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
-    
-    thread::spawn(move || {
-        let _ = Command::new(&cmd)
-            .args(&args)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
-    });
-    
-    Ok(())
+pub fn set_last_status(code: i32) {
+    LAST_STATUS.store(code, Ordering::Relaxed);
+}
+
+static PIPE_STATUS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// Exit statuses of each command of the most recent foreground pipeline (`$PIPESTATUS`)
+pub fn pipe_status() -> Vec<i32> {
+    PIPE_STATUS.lock().unwrap().clone()
+}
+
+pub fn set_pipe_status(statuses: Vec<i32>) {
+    *PIPE_STATUS.lock().unwrap() = statuses;
+}
+
+/// The status of a whole pipeline: that of its last command, or with the
+/// `pipefail` option that of the last command to fail
+pub fn pipeline_status(statuses: &[i32]) -> i32 {
+    if options::is_set("pipefail")
+        && let Some(failed) = statuses.iter().rev().find(|&&s| s != 0) {
+        return *failed;
+    }
+    statuses.last().copied().unwrap_or(0)
+}
+
+/// A `break`, `continue` or `return` on its way out to the loop or function
+/// it applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Break(usize),
+    Continue(usize),
+    Return,
+    /// A foreground command was killed by Ctrl-C: unwind all the way to the prompt
+    Interrupt,
+}
+
+static FLOW: Mutex<Option<Flow>> = Mutex::new(None);
+static LOOP_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static CALL_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// The pending `break`, `continue`, `return` or interrupt, if any. Lists stop running commands
+/// while one is pending.
+pub fn flow() -> Option<Flow> {
+    *FLOW.lock().unwrap()
+}
+
+pub fn set_flow(flow: Option<Flow>) {
+    *FLOW.lock().unwrap() = flow;
+}
+
+pub fn take_flow() -> Option<Flow> {
+    FLOW.lock().unwrap().take()
+}
+
+/// How many loops are running, for `break` and `continue` to check against
+pub fn loop_depth() -> usize {
+    LOOP_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Run `f` as the body of a loop
+pub fn in_loop<T>(f: impl FnOnce() -> T) -> T {
+    LOOP_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    LOOP_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+/// How many functions and sourced files are running, for `return` to check against
+pub fn call_depth() -> usize {
+    CALL_DEPTH.load(Ordering::Relaxed)
+}
+
+/// Run `f` as a function or sourced file, which `return` leaves. Loops of the
+/// caller are out of reach of `break` and `continue` inside it.
+pub fn in_call<T>(f: impl FnOnce() -> T) -> T {
+    CALL_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let loops = LOOP_DEPTH.swap(0, Ordering::Relaxed);
+    let result = f();
+    LOOP_DEPTH.store(loops, Ordering::Relaxed);
+    CALL_DEPTH.fetch_sub(1, Ordering::Relaxed);
+
+    if flow() == Some(Flow::Return) {
+        set_flow(None);
+    }
+    result
+}
+
+/// Run `f` in a forked copy of the shell and return the child's pid.
+/// The child exits with the status `f` returns.
+pub fn fork_shell(f: impl FnOnce() -> i32) -> Result<i32> {
+    let _ = io::stdout().flush();
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => {
+            jobs::enter_subshell();
+            let status = f();
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(status) }
+        }
+        pid => Ok(pid),
+    }
 }
 
-pub fn execute_with_redirect(cmd: &[String], redirects: &[Redirect]) -> Result<()> {
-    let (program, args) = cmd.split_first().unwrap();
-    let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+/// Report a command that could not be started and return its status
+fn spawn_failed(cmd: &str, e: Error) -> i32 {
+    match e.kind() {
+        ErrorKind::NotFound => {
+            eprintln!("shesh: command not found: {}", cmd);
+            127
+        }
+        ErrorKind::PermissionDenied => {
+            eprintln!("shesh: permission denied: {}", cmd);
+            126
+        }
+        _ => {
+            eprintln!("shesh: {}: {}", cmd, e);
+            126
+        }
+    }
+}
+
+fn open_failed(file: &str, e: Error) -> Error {
+    Error::new(e.kind(), format!("shesh: {}: {}", file, e))
+}
+
+/// A command that will run with the shell's exported variables
+fn new_command(program: &str) -> Command {
     let mut command = Command::new(program);
-    
+    vars::apply_env(&mut command);
+    command
+}
+
+/// Text shown for a job in `jobs` and in notifications
+fn job_text(commands: &[Vec<String>]) -> String {
+    commands.iter()
+        .map(|argv| argv.join(" "))
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+/// Duplicate whatever `fd` currently refers to, looking through the
+/// redirections already applied before falling back to the shell's own fd
+fn dup_fd(table: &[(i32, Option<OwnedFd>)], fd: i32) -> Result<OwnedFd> {
+    let bad = || Error::new(ErrorKind::InvalidInput, format!("shesh: {}: bad file descriptor", fd));
+    match table.iter().find(|(n, _)| *n == fd) {
+        Some((_, Some(target))) => target.try_clone(),
+        Some((_, None)) => Err(bad()),
+        None if fd < 0 => Err(bad()),
+        None => unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned().map_err(|_| bad()),
+    }
+}
+
+/// A pipe that reads back `text`. The text is written by a forked process so
+/// that a document larger than the pipe buffer cannot block the shell, and
+/// so that the shell holds no write end for later forked stages to inherit.
+fn text_pipe(text: String) -> Result<OwnedFd> {
+    let (reader, mut writer) = io::pipe()?;
+    let reader_fd = reader.as_raw_fd();
+    let pid = fork_shell(|| {
+        unsafe { libc::close(reader_fd) };
+        // The reader may exit without reading everything
+        let _ = writer.write_all(text.as_bytes());
+        0
+    })?;
+    drop(writer);
+
+    // Nothing else waits for the writer
+    thread::spawn(move || unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) });
+    Ok(reader.into())
+}
+
+/// The lowest fd that is clear of every fd in a redirection table
+fn fd_floor(table: &[(i32, Option<OwnedFd>)]) -> i32 {
+    table.iter().map(|(fd, _)| fd + 1).max().unwrap_or(0).max(10)
+}
+
+/// Open the files of a list of redirections, applying them left to right on
+/// top of `table`, which holds fds already set up such as pipeline ends.
+/// Returns what each redirected fd ends up referring to, `None` if closed.
+/// The open files are numbered above every fd being redirected, so that
+/// putting one in place never overwrites another that is still to be used.
+fn open_redirects(
+    mut table: Vec<(i32, Option<OwnedFd>)>,
+    redirects: &[Redirect],
+) -> Result<Vec<(i32, Option<OwnedFd>)>> {
     for redirect in redirects {
-        match redirect {
-            Redirect::Output(file) => {
-                command.stdout(File::create(file)?);
+        let (fd, target) = match redirect {
+            Redirect::Output(fd, file) => {
+                (*fd, Some(File::create(file).map_err(|e| open_failed(file, e))?.into()))
             }
-            Redirect::Append(file) => {
-                command.stdout(OpenOptions::new().append(true).create(true).open(file)?);
+            Redirect::Append(fd, file) => {
+                let file = OpenOptions::new().append(true).create(true).open(file)
+                    .map_err(|e| open_failed(file, e))?;
+                (*fd, Some(file.into()))
+            }
+            Redirect::Input(fd, file) => {
+                (*fd, Some(File::open(file).map_err(|e| open_failed(file, e))?.into()))
+            }
+            Redirect::Dup(fd, source) => (*fd, Some(dup_fd(&table, *source)?)),
+            Redirect::Close(fd) => (*fd, None),
+            Redirect::Text(fd, text) => (*fd, Some(text_pipe(text.clone())?)),
+        };
+        table.retain(|(n, _)| *n != fd);
+        table.push((fd, target));
+    }
+
+    let floor = fd_floor(&table);
+    table.into_iter()
+        .map(|(fd, target)| Ok((fd, target.map(|t| raise_fd(&t, floor)).transpose()?)))
+        .collect()
+}
+
+/// A copy of `fd` numbered `floor` or above
+fn raise_fd(fd: &OwnedFd, floor: i32) -> Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, floor) } {
+        -1 => Err(Error::last_os_error()),
+        raw => Ok(unsafe { OwnedFd::from_raw_fd(raw) }),
+    }
+}
+
+fn apply_redirects(
+    command: &mut Command,
+    pipes: Vec<(i32, Option<OwnedFd>)>,
+    redirects: &[Redirect],
+) -> Result<()> {
+    let mut others = Vec::new();
+
+    for (fd, target) in open_redirects(pipes, redirects)? {
+        if fd > 2 {
+            others.push((fd, target));
+            continue;
+        }
+        // A closed standard fd is given /dev/null instead
+        let stdio = target.map_or_else(Stdio::null, Stdio::from);
+        match fd {
+            0 => command.stdin(stdio),
+            1 => command.stdout(stdio),
+            _ => command.stderr(stdio),
+        };
+    }
+
+    if !others.is_empty() {
+        unsafe {
+            command.pre_exec(move || {
+                for (fd, target) in &others {
+                    match target {
+                        Some(target) if libc::dup2(target.as_raw_fd(), *fd) == -1 => {
+                            return Err(Error::last_os_error());
+                        }
+                        Some(_) => {}
+                        None => { libc::close(*fd); }
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` with redirections applied to the shell's own fds, then put them back
+pub fn with_redirects<T>(redirects: &[Redirect], f: impl FnOnce() -> T) -> Result<T> {
+    if redirects.is_empty() {
+        return Ok(f());
+    }
+    let table = open_redirects(Vec::new(), redirects)?;
+    let floor = fd_floor(&table);
+
+    let _ = io::stdout().flush();
+    let saved: Vec<(i32, i32)> = table.iter()
+        .map(|(fd, target)| unsafe {
+            // -1 if the fd was not open before
+            let copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, floor);
+            match target {
+                Some(target) => libc::dup2(target.as_raw_fd(), *fd),
+                None => libc::close(*fd),
+            };
+            (*fd, copy)
+        })
+        .collect();
+    drop(table);
+
+    let result = f();
+
+    let _ = io::stdout().flush();
+    for (fd, copy) in saved {
+        unsafe {
+            if copy == -1 {
+                libc::close(fd);
+            } else {
+                libc::dup2(copy, fd);
+                libc::close(copy);
             }
-            Redirect::Input(file) => {
-                command.stdin(File::open(file)?);
+        }
+    }
+    Ok(result)
+}
+
+/// Start a prepared command as its own job, in the foreground or the background
+fn run_job(mut command: Command, program: &str, text: String, background: bool) -> i32 {
+    match jobs::spawn(&mut command, None, !background) {
+        Ok(child) => {
+            let pid = child.id() as i32;
+            if background {
+                jobs::add_background(pid, vec![(pid, None)], text);
+                0
+            } else {
+                jobs::wait_foreground(pid, vec![(pid, None)], text)[0]
             }
         }
+        Err(e) => spawn_failed(program, e),
     }
-    
-    command.args(args).status().map(|_| ())
 }
 
-pub fn execute_background_with_redirect(cmd: &[String], redirects: &[Redirect]) -> Result<()> {
-    let cmd = cmd.to_vec();
-    let redirects = redirects.to_vec();
-    
-    thread::spawn(move || {
-        let _ = execute_with_redirect(&cmd, &redirects);
-    });
-    
+pub fn execute(cmd: &str, args: &[&str]) -> i32 {
+    let mut command = new_command(cmd);
+    command.args(args);
+    run_job(command, cmd, format!("{} {}", cmd, args.join(" ")).trim_end().to_string(), false)
+}
+
+/// Detach a background command from the terminal's input, and from its
+/// output too when the `silentbg` option is set
+fn detach(command: &mut Command) {
+    command.stdin(Stdio::null());
+    if options::is_set("silentbg") {
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+}
+
+pub fn execute_background(cmd: &str, args: &[&str]) -> Result<()> {
+    let mut command = new_command(cmd);
+    command.args(args);
+    detach(&mut command);
+
+    run_job(command, cmd, format!("{} {}", cmd, args.join(" ")).trim_end().to_string(), true);
     Ok(())
 }
 
-pub fn execute_pipeline(commands: Vec<Vec<String>>) -> Result<()> {
-    let mut previous_output = None;
-    
-    for (i, cmd_parts) in commands.iter().enumerate() {
-        let (cmd, args) = cmd_parts.split_first().unwrap();
-        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
-        let mut command = Command::new(cmd);
-        command.args(args);
-        
-        if let Some(output) = previous_output.take() {
-            command.stdin(output);
+fn redirected_command(cmd: &[String], redirects: &[Redirect], background: bool) -> Result<i32> {
+    let mut command = new_command(cmd.first().map_or("", |s| s.as_str()));
+    if background {
+        detach(&mut command);
+    }
+    // A bare redirection such as `> file` only creates the file
+    let Some((program, args)) = cmd.split_first() else {
+        return open_redirects(Vec::new(), redirects).map(|_| 0);
+    };
+    apply_redirects(&mut command, Vec::new(), redirects)?;
+    command.args(args);
+    Ok(run_job(command, program, job_text(&[cmd.to_vec()]), background))
+}
+
+pub fn execute_with_redirect(cmd: &[String], redirects: &[Redirect]) -> Result<i32> {
+    redirected_command(cmd, redirects, false)
+}
+
+pub fn execute_background_with_redirect(cmd: &[String], redirects: &[Redirect]) -> Result<()> {
+    redirected_command(cmd, redirects, true).map(|_| ())
+}
+
+/// One command of a pipeline, after expansion
+pub struct Stage<'a> {
+    pub argv: &'a [String],
+    pub assignments: &'a [(String, String)],
+    pub redirects: &'a [Redirect],
+    /// Set for commands the shell runs itself, such as builtins, which then
+    /// run in a forked copy of the shell instead of `argv` being executed
+    pub run: Option<Box<dyn FnOnce() -> i32 + 'a>>,
+}
+
+/// What starting a pipeline stage gives: its pid, or the exit status it
+/// fails with when it cannot be started (the reason has been printed)
+type Started = std::result::Result<i32, i32>;
+
+/// Pids of a pipeline's processes, with their exit status once known
+type Procs = Vec<(i32, Option<i32>)>;
+
+fn report_failure(e: Error) -> i32 {
+    eprintln!("{}", e);
+    1
+}
+
+/// Start an external program as a pipeline stage
+fn start_program(
+    stage: &Stage,
+    input: Option<OwnedFd>,
+    output: Option<OwnedFd>,
+    pgid: Option<i32>,
+    background: bool,
+) -> Started {
+    let program = stage.argv.first().map_or("", |s| s.as_str());
+    let mut command = vars::with_assignments(stage.assignments, || new_command(program));
+    command.args(stage.argv.iter().skip(1));
+
+    if background {
+        detach(&mut command);
+    }
+    // The stage's own redirections apply after, and on top of, the pipes
+    let pipes = [(0, input), (1, output)].into_iter()
+        .filter(|(_, pipe)| pipe.is_some())
+        .collect();
+    apply_redirects(&mut command, pipes, stage.redirects).map_err(report_failure)?;
+
+    match jobs::spawn(&mut command, pgid, !background) {
+        Ok(child) => Ok(child.id() as i32),
+        Err(e) => Err(spawn_failed(program, e)),
+    }
+}
+
+/// Start a pipeline stage the shell runs itself in a forked copy of the shell.
+/// `next_input` is the read end of the stage's own output pipe, which the
+/// copy must not keep open or the stage would never see the reader go away.
+fn start_forked(
+    run: Box<dyn FnOnce() -> i32 + '_>,
+    redirects: &[Redirect],
+    input: Option<OwnedFd>,
+    output: Option<OwnedFd>,
+    next_input: Option<i32>,
+    pgid: Option<i32>,
+    background: bool,
+) -> Started {
+    jobs::fork(pgid, !background, || {
+        if let Some(fd) = next_input {
+            unsafe { libc::close(fd) };
+        }
+        let silent = background && options::is_set("silentbg");
+        let null = |fd: i32, write: bool| {
+            if let Ok(file) = OpenOptions::new().read(!write).write(write).open("/dev/null") {
+                unsafe { libc::dup2(file.as_raw_fd(), fd) };
+            }
+        };
+        match input {
+            Some(input) => unsafe { libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO); },
+            None if background => null(libc::STDIN_FILENO, false),
+            None => {}
+        }
+        match output {
+            Some(output) => unsafe { libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO); },
+            None if silent => null(libc::STDOUT_FILENO, true),
+            None => {}
         }
-        
-        if i < commands.len() - 1 {
-            let child = command.stdout(Stdio::piped()).spawn()?;
-            previous_output = child.stdout;
+        if silent {
+            null(libc::STDERR_FILENO, true);
+        }
+        with_redirects(redirects, run).unwrap_or_else(report_failure)
+    })
+    .map_err(report_failure)
+}
+
+/// Start every stage of a pipeline in one process group
+fn start_pipeline(stages: Vec<Stage>, background: bool) -> Result<(i32, Procs)> {
+    let count = stages.len();
+    let mut previous_output: Option<OwnedFd> = None;
+    let mut pgid = None;
+    let mut procs = Vec::new();
+
+    for (i, mut stage) in stages.into_iter().enumerate() {
+        let input = previous_output.take();
+        let output = if i < count - 1 {
+            let (reader, writer) = io::pipe()?;
+            previous_output = Some(reader.into());
+            Some(writer.into())
         } else {
-            command.status()?;
+            None
+        };
+
+        let started = match stage.run.take() {
+            Some(run) => {
+                let next_input = previous_output.as_ref().map(|fd| fd.as_raw_fd());
+                start_forked(run, stage.redirects, input, output, next_input, pgid, background)
+            }
+            None => start_program(&stage, input, output, pgid, background),
+        };
+        match started {
+            Ok(pid) => {
+                pgid.get_or_insert(pid);
+                procs.push((pid, None));
+            }
+            // A stage that fails to start leaves the next one reading nothing
+            Err(status) => procs.push((0, Some(status))),
         }
     }
-    Ok(())
+    Ok((pgid.unwrap_or(0), procs))
+}
+
+fn stages_text(stages: &[Stage]) -> String {
+    job_text(&stages.iter().map(|stage| stage.argv.to_vec()).collect::<Vec<_>>())
+}
+
+/// Run a pipeline in the foreground and return the status of each of its commands
+pub fn execute_pipeline(stages: Vec<Stage>) -> Result<Vec<i32>> {
+    let text = stages_text(&stages);
+    let (pgid, procs) = start_pipeline(stages, false)?;
+    Ok(jobs::wait_foreground(pgid, procs, text))
 }
 
-pub fn execute_background_pipeline(commands: Vec<Vec<String>>) -> Result<()> {
-    thread::spawn(move || {
-        let _ = execute_pipeline(commands);
-    });
+pub fn execute_background_pipeline(stages: Vec<Stage>) -> Result<()> {
+    let text = stages_text(&stages);
+    let (pgid, procs) = start_pipeline(stages, true)?;
+    jobs::add_background(pgid, procs, text);
     Ok(())
 }
@@ -0,0 +1,167 @@
+use std::{collections::BTreeMap, env, process::Command, sync::Mutex};
+
+/// A shell variable; `value` is `None` for names exported before being set
+struct Var {
+    value: Option<String>,
+    exported: bool,
+}
+
+static VARS: Mutex<BTreeMap<String, Var>> = Mutex::new(BTreeMap::new());
+
+/// Variables made local by a function, with what they were before, to put
+/// back when it returns
+type Scope = Vec<(String, Option<Var>)>;
+
+/// One scope for each running function
+static SCOPES: Mutex<Vec<Scope>> = Mutex::new(Vec::new());
+
+/// `$0`, the name of the running script or of the shell
+static SCRIPT_NAME: Mutex<String> = Mutex::new(String::new());
+
+/// `$1`, `$2`, ...
+static POSITIONAL: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Load the environment the shell was started with as exported variables
+pub fn init() {
+    let mut vars = VARS.lock().unwrap();
+    for (name, value) in env::vars_os() {
+        if let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) {
+            vars.insert(name, Var { value: Some(value), exported: true });
+        }
+    }
+}
+
+pub fn script_name() -> String {
+    match SCRIPT_NAME.lock().unwrap().as_str() {
+        "" => "shesh".to_string(),
+        name => name.to_string(),
+    }
+}
+
+pub fn set_script_name(name: &str) {
+    *SCRIPT_NAME.lock().unwrap() = name.to_string();
+}
+
+pub fn positional() -> Vec<String> {
+    POSITIONAL.lock().unwrap().clone()
+}
+
+/// Replace the positional parameters, returning the previous ones
+pub fn set_positional(args: Vec<String>) -> Vec<String> {
+    std::mem::replace(&mut POSITIONAL.lock().unwrap(), args)
+}
+
+/// Drop the first `n` positional parameters; false if there are fewer than `n`
+pub fn shift(n: usize) -> bool {
+    let mut positional = POSITIONAL.lock().unwrap();
+    if n > positional.len() {
+        return false;
+    }
+    positional.drain(..n);
+    true
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn get(name: &str) -> Option<String> {
+    VARS.lock().unwrap().get(name).and_then(|v| v.value.clone())
+}
+
+/// Set a variable, keeping its export attribute if it already has one
+pub fn set(name: &str, value: &str) {
+    let mut vars = VARS.lock().unwrap();
+    let var = vars.entry(name.to_string()).or_insert(Var { value: None, exported: false });
+    var.value = Some(value.to_string());
+}
+
+/// Run `f` with `assignments` exported, then put the variables back as they were
+pub fn with_assignments<T>(assignments: &[(String, String)], f: impl FnOnce() -> T) -> T {
+    let saved: Vec<(String, Option<Var>)> = {
+        let mut vars = VARS.lock().unwrap();
+        assignments.iter()
+            .map(|(name, value)| {
+                let var = Var { value: Some(value.clone()), exported: true };
+                (name.clone(), vars.insert(name.clone(), var))
+            })
+            .collect()
+    };
+
+    let result = f();
+
+    let mut vars = VARS.lock().unwrap();
+    for (name, old) in saved.into_iter().rev() {
+        match old {
+            Some(var) => vars.insert(name, var),
+            None => vars.remove(&name),
+        };
+    }
+    result
+}
+
+/// Run `f` as a function body with its own scope for `local` variables
+pub fn with_scope<T>(f: impl FnOnce() -> T) -> T {
+    SCOPES.lock().unwrap().push(Vec::new());
+    let result = f();
+
+    let saved = SCOPES.lock().unwrap().pop().unwrap_or_default();
+    let mut vars = VARS.lock().unwrap();
+    for (name, old) in saved.into_iter().rev() {
+        match old {
+            Some(var) => vars.insert(name, var),
+            None => vars.remove(&name),
+        };
+    }
+    result
+}
+
+/// Make a variable local to the running function, optionally assigning it.
+/// Returns false when no function is running.
+pub fn make_local(name: &str, value: Option<&str>) -> bool {
+    let mut scopes = SCOPES.lock().unwrap();
+    let Some(scope) = scopes.last_mut() else {
+        return false;
+    };
+
+    let mut vars = VARS.lock().unwrap();
+    if !scope.iter().any(|(n, _)| n == name) {
+        scope.push((name.to_string(), vars.remove(name)));
+    }
+    if let Some(value) = value {
+        vars.insert(name.to_string(), Var { value: Some(value.to_string()), exported: false });
+    }
+    true
+}
+
+/// Mark a variable for export, optionally assigning it at the same time
+pub fn export(name: &str, value: Option<&str>) {
+    let mut vars = VARS.lock().unwrap();
+    let var = vars.entry(name.to_string()).or_insert(Var { value: None, exported: false });
+    var.exported = true;
+    if let Some(value) = value {
+        var.value = Some(value.to_string());
+    }
+}
+
+pub fn unset(name: &str) {
+    VARS.lock().unwrap().remove(name);
+}
+
+/// Exported variables with their values, sorted by name
+pub fn exported() -> Vec<(String, Option<String>)> {
+    VARS.lock().unwrap()
+        .iter()
+        .filter(|(_, v)| v.exported)
+        .map(|(n, v)| (n.clone(), v.value.clone()))
+        .collect()
+}
+
+/// Give a child process exactly the exported variables as its environment
+pub fn apply_env(command: &mut Command) {
+    command.env_clear().envs(
+        exported().into_iter().filter_map(|(name, value)| Some((name, value?)))
+    );
+}
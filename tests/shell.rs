@@ -0,0 +1,69 @@
+use std::{
+    process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Run `shesh -c script`, failing the test if it has not finished in time
+fn run(script: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_shesh"))
+        .args(["-c", script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > Duration::from_secs(10) {
+            let _ = child.kill();
+            panic!("timed out: {}", script);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(script: &str) -> String {
+    String::from_utf8_lossy(&run(script).stdout).into_owned()
+}
+
+#[test]
+fn forked_stages_see_the_reader_exit() {
+    assert_eq!(stdout("{ yes; } | head -1"), "y\n");
+    assert_eq!(stdout("f() { yes; }; f | head -1"), "y\n");
+    assert_eq!(stdout("for i in 1; do yes; done | head -1"), "y\n");
+}
+
+#[test]
+fn here_string_reaches_forked_stages() {
+    let script = "x=$(seq 1 100000); cat <<< \"$x\" | { wc -l; }";
+    assert_eq!(stdout(script), "100000\n");
+    let script = "x=$(seq 1 100000); f() { wc -l; }; cat <<< \"$x\" | f";
+    assert_eq!(stdout(script), "100000\n");
+}
+
+#[test]
+fn sigint_to_a_child_does_not_stop_a_script() {
+    assert_eq!(stdout("sh -c 'kill -INT $$'; echo after $?"), "after 130\n");
+}
+
+#[test]
+fn background_and_or_list_runs_as_one_job() {
+    assert_eq!(stdout("false && echo WRONG & sleep 0.3; echo done"), "done\n");
+    assert_eq!(stdout("sleep 0.3 && echo second & echo first; sleep 0.6"), "first\nsecond\n");
+}
+
+#[test]
+fn zero_padded_positional_zero_is_the_script_name() {
+    assert_eq!(stdout("echo ${00} ${#00}"), "shesh 5\n");
+}
+
+#[test]
+fn unset_parameter_error_ends_a_script() {
+    let output = run("echo ${Z:?oops}; echo after");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "shesh: Z: oops\n");
+}